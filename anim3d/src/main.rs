@@ -1,12 +1,20 @@
-use engine3d::{anim::Bone, events::*, geom::*, render::InstanceGroups, run, sound, Engine, DT};
+use engine3d::{
+    anim::Bone,
+    anim_state::{AnimStateMachine, StateDef, StateMachineDef},
+    animator::AnimEvent,
+    events::*,
+    geom::*,
+    render::InstanceGroups,
+    run, Engine, DT,
+};
 use winit;
 
 #[derive(Clone, Debug)]
 pub struct Player {
     pos: Pos3,
+    heading: f32,
     bones: Vec<Bone>,
-    t: f32,
-    anim: usize,
+    states: AnimStateMachine,
 }
 
 impl Player {
@@ -16,25 +24,50 @@ impl Player {
         assets: &engine3d::assets::Assets,
         igs: &mut InstanceGroups,
     ) {
-        let anim = assets.get_anim(rules.player_anims[self.anim]).unwrap();
-        let rig = assets.get_rig(rules.player_rig).unwrap();
-        rig.reset(&mut self.bones);
-        anim.sample(self.t, rig, &mut self.bones);
-        igs.render_anim(
-            rules.player_model,
-            engine3d::render::InstanceRaw {
-                model: (Mat4::from_translation(self.pos.to_vec()) * Mat4::from_scale(0.01)).into(),
-            },
-            self.bones.clone(),
-        );
+        self.states.pose(assets, rules.player_rig, &mut self.bones);
+        let instance = engine3d::render::InstanceRaw {
+            model: (Mat4::from_translation(self.pos.to_vec())
+                * Mat4::from_angle_y(cgmath::Rad(self.heading))
+                * Mat4::from_scale(0.01))
+            .into(),
+        };
+        igs.render_anim(rules.player_model, instance, self.bones.clone());
+        // A ball balanced on the fox's head
+        let head = assets
+            .get_rig(rules.player_rig)
+            .and_then(|rig| rig.socket_skinned(&self.bones, "b_Head_05"));
+        if let Some(head) = head {
+            igs.render(
+                rules.hat_model,
+                instance.attached(head, Mat4::from_scale(8.0)),
+            );
+        }
     }
-    fn integrate(&mut self, rules: &GameData) {
-        self.t += DT;
-        if self.t > 4.0 {
-            self.t = 0.0;
-            self.anim += 1;
-            self.anim = self.anim % rules.player_anims.len();
-            println!("Switch to anim {}", self.anim);
+    fn integrate(&mut self, events: &engine3d::events::Events, assets: &engine3d::assets::Assets) {
+        // W to walk, shift+W to run
+        let speed = match (
+            events.key_held(KeyCode::W),
+            events.key_held(KeyCode::LShift),
+        ) {
+            (true, true) => 2.0,
+            (true, false) => 1.0,
+            _ => 0.0,
+        };
+        self.states.set_param("speed", speed);
+        let state = self.states.state().to_string();
+        self.states.update(assets, DT);
+        // The clips move the fox; it's drawn at 1/100 scale
+        let motion = self.states.animator_mut().take_root_motion();
+        let facing = Quat::from_angle_y(cgmath::Rad(self.heading));
+        self.pos += facing.rotate_vector(motion.translation) * 0.01;
+        self.heading += motion.yaw;
+        if self.states.state() != state {
+            println!("Switch to {}", self.states.state());
+        }
+        for ev in self.states.events() {
+            if let AnimEvent::Marker { name, .. } = ev {
+                println!("{}", name);
+            }
         }
     }
 }
@@ -100,29 +133,52 @@ struct Game {
 struct GameData {
     player_model: engine3d::assets::ModelRef,
     player_rig: engine3d::assets::RigRef,
-    player_anims: Vec<engine3d::assets::AnimRef>,
+    hat_model: engine3d::assets::ModelRef,
 }
 
 impl engine3d::Game for Game {
     type StaticData = GameData;
     fn start(engine: &mut Engine) -> (Self, Self::StaticData) {
+        let fox = engine.load_scene("khronos/Fox/glTF/Fox.gltf").unwrap();
+        let states_path = engine.assets.asset_path("khronos/Fox/fox_states.json");
+        let mut states = StateMachineDef::load(states_path)
+            .and_then(|def| AnimStateMachine::new(def, |clip| fox.anim(clip)))
+            .unwrap_or_else(|e| {
+                // Keep the fox moving on its first clip so the demo still runs
+                eprintln!("Couldn't set up the fox's states: {}", e);
+                let only = StateDef {
+                    name: "only".into(),
+                    clip: fox.anims[0].name.clone(),
+                    speed: 1.0,
+                    mode: Default::default(),
+                    events: vec![],
+                };
+                let def = StateMachineDef {
+                    initial: only.name.clone(),
+                    states: vec![only],
+                    transitions: vec![],
+                };
+                AnimStateMachine::new(def, |_| Some(fox.anims[0].handle))
+                    .expect("a single state with a clip is always valid")
+            });
+        states
+            .animator_mut()
+            .extract_root_motion(fox.rigs[0].handle, "b_Hip_01");
         let player = Player {
             pos: Pos3::new(0.0, 5.0, 0.0),
+            heading: 0.0,
             bones: vec![engine3d::anim::Bone::default(); engine3d::render::BONE_MAX],
-            t: 0.0,
-            anim: 0,
+            states,
         };
-        let (player_models, player_rigs, player_anims) =
-            engine.load_gltf("khronos/Fox/glTF/Fox.gltf");
         (
             Self {
                 player,
                 camera: OrbitCamera::new(),
             },
             GameData {
-                player_model: player_models[0],
-                player_rig: player_rigs[0],
-                player_anims,
+                player_model: fox.model().unwrap(),
+                player_rig: fox.rigs[0].handle,
+                hat_model: engine.load_model_or_fallback("sphere.obj"),
             },
         )
     }
@@ -134,8 +190,8 @@ impl engine3d::Game for Game {
     ) {
         self.player.render(rules, assets, igs);
     }
-    fn update(&mut self, rules: &Self::StaticData, engine: &mut Engine, sound: &sound::Sound) {
-        self.player.integrate(rules);
+    fn update(&mut self, _rules: &Self::StaticData, engine: &mut Engine) {
+        self.player.integrate(&engine.events, &engine.assets);
         self.camera.update(&engine.events, &self.player);
         self.camera.update_camera(engine.camera_mut());
     }
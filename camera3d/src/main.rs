@@ -1,5 +1,11 @@
 use engine3d::{
-    collision, events::*, geom::*, render::InstanceGroups, run, save_load, sound, Engine, DT,
+    collision,
+    events::*,
+    geom::*,
+    mixer::{Bus, MixerSettings},
+    music::{Playlist, Track},
+    render::InstanceGroups,
+    run, save_load, sound, Engine, DT,
 };
 use rand;
 // use rodio::{source::SineWave, source::Source, SpatialSink};
@@ -347,12 +353,14 @@ struct Game {
     use_alt_cam: bool,
     // sound: sound::Sound,
 }
+const AUDIO_SETTINGS: &str = "audio_settings.json";
+
 struct GameData {
     marble_model: engine3d::assets::ModelRef,
     box_model: engine3d::assets::ModelRef,
     wall_model: engine3d::assets::ModelRef,
     player_model: engine3d::assets::ModelRef,
-    // sound: sound::Sound,
+    beep: Option<sound::SoundRef>,
 }
 
 impl engine3d::Game for Game {
@@ -497,10 +505,37 @@ impl engine3d::Game for Game {
             },
         ];
         // let cubes = vec![];
-        let wall_model = engine.load_model("floor.obj");
-        let marble_model = engine.load_model("sphere.obj");
-        let player_model = engine.load_model("sphere.obj");
-        let box_model = engine.load_model("cube.obj");
+        let wall_model = engine.load_model_or_fallback("floor.obj");
+        let marble_model = engine.load_model_or_fallback("sphere.obj");
+        let player_model = engine.load_model_or_fallback("sphere.obj");
+        let box_model = engine.load_model_or_fallback("cube.obj");
+        match MixerSettings::load(AUDIO_SETTINGS) {
+            Ok(settings) => engine.sound.mixer_mut().apply_settings(&settings),
+            // No settings saved yet is fine
+            Err(e) if std::path::Path::new(AUDIO_SETTINGS).exists() => eprintln!("{}", e),
+            Err(_) => {}
+        }
+        // The music follows which camera is in use
+        let music = engine.sound.music_mut();
+        music.add_cue(
+            "orbit",
+            Playlist {
+                repeat: true,
+                ..Playlist::new(vec![Track::new("music.ogg")])
+            },
+        );
+        music.add_cue(
+            "top_down",
+            Playlist {
+                repeat: true,
+                ..Playlist::new(vec![Track::new("music.mp3")])
+            },
+        );
+        let beep = engine
+            .sound
+            .load("beep3.ogg")
+            .map_err(|e| eprintln!("{}", e))
+            .ok();
         (
             Self {
                 // camera_controller,
@@ -524,6 +559,7 @@ impl engine3d::Game for Game {
                 marble_model,
                 box_model,
                 player_model,
+                beep,
             },
         )
     }
@@ -539,7 +575,7 @@ impl engine3d::Game for Game {
         self.cubes.iter().for_each(|c| c.render(rules, igs));
         // self.camera.render(rules, igs);
     }
-    fn update(&mut self, _rules: &Self::StaticData, engine: &mut Engine, sound: &sound::Sound) {
+    fn update(&mut self, rules: &Self::StaticData, engine: &mut Engine) {
 
         // If the player touches the emmiting box move everything down
         if self.player.body.touching(&self.cubes[0].body) {
@@ -689,69 +725,36 @@ impl engine3d::Game for Game {
 
         if self.use_alt_cam {
             self.alt_camera.update_camera(engine.camera_mut());
+            engine.sound.music_mut().cue("top_down");
         } else {
             self.camera.update_camera(engine.camera_mut());
+            engine.sound.music_mut().cue("orbit");
+        }
+        if engine.events.key_pressed(KeyCode::M) {
+            let mixer = engine.sound.mixer_mut();
+            mixer.set_muted(Bus::Master, !mixer.is_muted(Bus::Master));
+            if let Err(e) = mixer.settings().save(AUDIO_SETTINGS) {
+                eprintln!("{}", e);
+            }
         }
         // play sound
         if engine.events.key_pressed(KeyCode::H) {
-
-            // Save the velocity and set it to zero
-            let temp_lin = self.player.body.lin_mom;
-            let temp_ang = self.player.body.ang_mom;
-            self.player.body.lin_mom = Vec3::zero();
-            self.player.body.ang_mom = Vec3::zero();
-
-            sound.sink.play();
             let cube_pos = self.cubes[0].body.c;
             println!("cubex pos: {}", cube_pos[0]);
             println!("cube z pos: {}", cube_pos[2]);
             println!("my x pos: {}", self.player.body.c[0]);
             println!("my z pos: {}", self.player.body.c[2]);
 
-            let x_diff = cube_pos[0] - self.player.body.c[0];
-            let z_diff = cube_pos[2] - self.player.body.c[2];
-            // top right
-            if x_diff > 0.0 && z_diff < 0.0 {
-                // we are to right of cube
-                println!("xdip: {}", x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_left_to_right(x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_bottom_to_top(z_diff);
-            }
-            // bottom left
-            if x_diff > 0.0 && z_diff > 0.0 {
-                // we are to right of cube
-                println!("xdip: {}", x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_left_to_right(x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_top_to_bottom(z_diff);
+            // A beep that travels from us towards the cube, so you can hear where it is
+            if let Some(beep) = rules.beep {
+                engine.sound.play_moving(
+                    beep,
+                    self.player.body.c.into(),
+                    cube_pos.into(),
+                    std::time::Duration::from_secs(1),
+                );
             }
-            // top right
-            if x_diff < 0.0 && z_diff > 0.0 {
-                // we are to right of cube
-                println!("xdip: {}", x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_right_to_left(x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_top_to_bottom(z_diff);
-            }
-            // bottom right
-            if x_diff < 0.0 && z_diff < 0.0 {
-                // we are to right of cube
-                println!("xdip: {}", x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_right_to_left(x_diff);
-                sound.add_sound("content/beep3.ogg");
-                sound.play_top_to_bottom(z_diff);
-                self.player.body.lin_mom = temp_lin;
-                self.player.body.ang_mom = temp_ang;
-            }
-
         }
-
-        sound.sink.pause();
     }
 }
 
@@ -0,0 +1,157 @@
+// Times sampling the Fox's run cycle for a crowd, one character at a time and
+// in one batch, and finding keyframes by linear scan and by binary search.
+// Run with `cargo bench -p engine3d`.
+use engine3d::anim::{Anim, Bone, Rig};
+use std::cmp::Ordering;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const CROWD: usize = 64;
+const FRAMES: usize = 600;
+// Timed runs of each case; the fastest is the one least disturbed by the rest of the machine
+const RUNS: usize = 7;
+
+fn time(label: &str, samples: usize, mut f: impl FnMut()) -> Duration {
+    // Warm up caches first
+    f();
+    let elapsed = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    println!(
+        "{:<24} {:>10.2?} best of {}, {:>8.0} ns each",
+        label,
+        elapsed,
+        RUNS,
+        elapsed.as_nanos() as f64 / samples as f64
+    );
+    elapsed
+}
+
+// The key before t, as anim.rs finds it
+fn locate_binary(times: &[f32], t: f32) -> usize {
+    let last = times.len() - 1;
+    if t <= times[0] {
+        return 0;
+    }
+    if t >= times[last] {
+        return last;
+    }
+    match times.binary_search_by(|key| {
+        if *key <= t {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    }) {
+        Ok(k) | Err(k) => k - 1,
+    }
+}
+
+fn locate_linear(times: &[f32], t: f32) -> usize {
+    times.iter().rposition(|key| *key <= t).unwrap_or(0)
+}
+
+fn main() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../content/khronos/Fox/glTF/Fox.gltf");
+    let (g, bufs, _images) = gltf::import(&path).expect("Fox.gltf");
+    let skin = g.skins().next().expect("a skin");
+    let rig = Rig::from_gltf(&g, &bufs, skin).expect("rig");
+    let run = g
+        .animations()
+        .find(|a| a.name() == Some("Run"))
+        .expect("a Run animation");
+    let anim = Anim::from_gltf(&g, &bufs, run).expect("animation");
+    let binding = anim.bind(&rig, None);
+    let joints = rig.joint_count();
+    // Spread the crowd out over the cycle so they don't all hit the same keys
+    let offsets: Vec<f32> = (0..CROWD)
+        .map(|i| i as f32 / CROWD as f32 * anim.duration())
+        .collect();
+    let samples = CROWD * FRAMES;
+
+    let mut poses = vec![vec![Bone::default(); joints]; CROWD];
+    let one_by_one = time("sample_bound", samples, || {
+        for frame in 0..FRAMES {
+            let t = frame as f32 / 60.0;
+            for (pose, offset) in poses.iter_mut().zip(offsets.iter()) {
+                rig.reset(pose);
+                anim.sample_bound(t + offset, &rig, &binding, pose);
+            }
+        }
+    });
+
+    let mut batch = vec![Bone::default(); joints * CROWD];
+    let mut times = vec![0.0; CROWD];
+    let batched = time("sample_batch", samples, || {
+        for frame in 0..FRAMES {
+            let t = frame as f32 / 60.0;
+            for (time, offset) in times.iter_mut().zip(offsets.iter()) {
+                *time = t + offset;
+            }
+            for pose in batch.chunks_exact_mut(joints) {
+                rig.reset(pose);
+            }
+            anim.sample_batch(&times, &rig, &binding, &mut batch);
+        }
+    });
+    // Both leave the last frame's poses behind
+    let flat: Vec<Bone> = poses.iter().flatten().copied().collect();
+    assert_eq!(
+        bytemuck::cast_slice::<Bone, f32>(&flat),
+        bytemuck::cast_slice::<Bone, f32>(&batch),
+        "batching should give the same poses"
+    );
+    println!(
+        "batching is {:.2}x as fast",
+        one_by_one.as_secs_f64() / batched.as_secs_f64()
+    );
+
+    // Every channel's key times, for the same crowd and frames
+    let key_times: Vec<Vec<f32>> = g
+        .animations()
+        .find(|a| a.name() == Some("Run"))
+        .expect("a Run animation")
+        .channels()
+        .filter_map(|c| {
+            c.reader(|b| Some(&bufs[b.index()]))
+                .read_inputs()
+                .map(|ts| ts.collect())
+        })
+        .collect();
+    let lookups = samples * key_times.len();
+    // Summing the keys found keeps the searches from being optimized away
+    let mut found = (0, 0);
+    let linear = time("locate, linear scan", lookups, || {
+        for frame in 0..FRAMES {
+            let t = frame as f32 / 60.0;
+            for offset in offsets.iter() {
+                let t = (t + offset) % anim.duration();
+                for times in key_times.iter() {
+                    found.0 += locate_linear(times, t);
+                }
+            }
+        }
+    });
+    let binary = time("locate, binary search", lookups, || {
+        for frame in 0..FRAMES {
+            let t = frame as f32 / 60.0;
+            for offset in offsets.iter() {
+                let t = (t + offset) % anim.duration();
+                for times in key_times.iter() {
+                    found.1 += locate_binary(times, t);
+                }
+            }
+        }
+    });
+    assert_eq!(found.0, found.1, "both searches should find the same keys");
+    println!(
+        "binary search is {:.2}x as fast over {} keys per channel",
+        linear.as_secs_f64() / binary.as_secs_f64(),
+        key_times.iter().map(|ts| ts.len()).max().unwrap_or(0)
+    );
+}
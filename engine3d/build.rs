@@ -39,6 +39,10 @@ impl ShaderData {
     }
 }
 
+// The renderer embeds the SPIR-V compiled here, so a game runs without its
+// shader sources and keeps drawing when a live edit fails to compile.  The
+// runtime compiler in src/shaders.rs only builds the stages a shader directory
+// replaces, which is why shaderc is needed both here and in the engine.
 fn main() -> Result<()> {
     // Collect all shaders recursively within /src/
     let mut shader_paths = [
@@ -1,112 +1,561 @@
+use crate::assets::AssetErrorKind;
 use crate::geom::*;
-use crate::model::{DrawModel, Model};
+use crate::model::{DrawModel, Material, Mesh, Model};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Range;
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Bone {
     pub translation: [f32; 3],
-    pub ignored: f32,
+    pub scale: f32,         // uniform; non-uniform joint scale is averaged
     pub rotation: [f32; 4], // a quaternion
 }
 
+impl Default for Bone {
+    fn default() -> Self {
+        Self {
+            translation: [0.0; 3],
+            scale: 1.0,
+            rotation: [0.0, 0.0, 0.0, 1.0],
+        }
+    }
+}
+
+impl Bone {
+    pub(crate) fn decomposed(&self) -> cgmath::Decomposed<Vec3, Quat> {
+        cgmath::Decomposed {
+            scale: self.scale,
+            rot: self.rotation.into(),
+            disp: self.translation.into(),
+        }
+    }
+}
+
 pub struct Joint {
-    children: [u8; 4],
+    name: String,
+    parent: Option<usize>,
+    children: Vec<usize>,
     // In local coordinate frame, binding pose
     translation: Vec3,
     rotation: Quat,
+    scale: Vec3,
 }
 
 pub struct Rig {
     joints: Vec<Joint>,
     ibms: Vec<Mat4>,
-    // erk, hack, only used during loading
-    nodes_to_joints: std::collections::BTreeMap<usize, u8>, // names:Vec<Option<String>>
+    // The same, as scale, rotation and translation, so skinning needn't go through matrices
+    ibm_parts: Vec<cgmath::Decomposed<Vec3, Quat>>,
+    // Joint indices with every parent before its children
+    order: Vec<usize>,
+    names: HashMap<String, usize>,
+}
+
+// Animations find joints by name, so unnamed nodes need a stable stand-in
+fn node_name(n: &gltf::Node) -> String {
+    n.name()
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| format!("node{}", n.index()))
 }
 
 impl Rig {
-    pub fn from_gltf(_g: &gltf::Document, bufs: &[gltf::buffer::Data], skin: gltf::Skin) -> Self {
+    pub fn from_gltf(
+        _g: &gltf::Document,
+        bufs: &[gltf::buffer::Data],
+        skin: gltf::Skin,
+    ) -> Result<Self, AssetErrorKind> {
+        // Every joint gets a slot in the bone uniform buffer
+        let joint_count = skin.joints().count();
+        if joint_count > crate::render::BONE_MAX {
+            return Err(AssetErrorKind::Invalid(format!(
+                "skin has {} joints, more than the {} the renderer supports",
+                joint_count,
+                crate::render::BONE_MAX
+            )));
+        }
         let reader = skin.reader(|buffer| Some(&bufs[buffer.index()]));
-        use std::collections::BTreeMap;
-        let mut nodes_to_joints: BTreeMap<usize, u8> = skin
+        let nodes_to_joints: HashMap<usize, usize> = skin
             .joints()
             .enumerate()
-            .map(|(ji, n)| {
-                assert!(ji < 255);
-                (n.index(), ji as u8)
-            })
+            .map(|(ji, n)| (n.index(), ji))
             .collect();
-        let joints: Vec<_> = skin
+        let mut joints = skin
             .joints()
-            .enumerate()
-            .map(|(ji, n)| {
-                assert!(ji < 255);
-                nodes_to_joints.insert(n.index(), ji as u8);
-                let (translation, rotation) = match n.transform() {
-                    gltf::scene::Transform::Matrix { matrix: _m4 } => {
-                        // let m4 = Mat4::from(m4);
-                        panic!("Oh well, don't support matrices")
-                    }
-                    gltf::scene::Transform::Decomposed {
-                        translation: tr,
-                        rotation: rot,
-                        scale: _sc,
-                    } => (Vec3::from(tr), Quat::from(rot)),
-                };
-                let mut children = [255, 255, 255, 255];
-                for (ci, c) in n.children().enumerate() {
-                    assert!(ci < 4);
-                    let cidx = nodes_to_joints[&c.index()];
-                    children[ci] = cidx;
-                }
+            .map(|n| {
+                // Matrix transforms come out decomposed too
+                let (tr, rot, sc) = n.transform().decomposed();
+                // Meshes, lights and attachment points often hang off bones;
+                // they're scene nodes, not part of the rig
+                let children = n
+                    .children()
+                    .filter_map(|c| nodes_to_joints.get(&c.index()).copied())
+                    .collect();
                 Joint {
+                    name: node_name(&n),
+                    parent: None,
                     children,
-                    translation,
-                    rotation,
+                    translation: Vec3::from(tr),
+                    rotation: Quat::from(rot),
+                    scale: Vec3::from(sc),
                 }
             })
+            .collect::<Vec<_>>();
+        for ji in 0..joints.len() {
+            for ci in joints[ji].children.clone() {
+                joints[ci].parent = Some(ji);
+            }
+        }
+        // glTF doesn't promise parents come first in skin.joints, so sort them
+        let mut order = Vec::with_capacity(joints.len());
+        let mut stack: Vec<usize> = (0..joints.len())
+            .filter(|ji| joints[*ji].parent.is_none())
+            .rev()
             .collect();
-        Self {
-            ibms: reader
-                .read_inverse_bind_matrices()
-                .map(|ibms| ibms.map(Mat4::from).collect())
-                .unwrap_or_else(|| vec![Mat4::identity(); joints.len()]),
-            joints,
-            nodes_to_joints,
+        while let Some(ji) = stack.pop() {
+            order.push(ji);
+            stack.extend(joints[ji].children.iter().rev());
+        }
+        if order.len() != joints.len() {
+            return Err(AssetErrorKind::Invalid("skin joints form a cycle".into()));
         }
+        let ibms: Vec<Mat4> = reader
+            .read_inverse_bind_matrices()
+            .map(|ibms| ibms.map(Mat4::from).collect())
+            .unwrap_or_else(|| vec![Mat4::identity(); joints.len()]);
+        let ibm_parts = ibms
+            .iter()
+            .enumerate()
+            .map(|(ji, ibm)| {
+                decompose(*ibm).map_err(|why| {
+                    AssetErrorKind::Invalid(format!(
+                        "inverse bind matrix of joint {:?} {}",
+                        joints.get(ji).map_or("", |j| j.name.as_str()),
+                        why
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            ibm_parts,
+            ibms,
+            names: joints
+                .iter()
+                .enumerate()
+                .map(|(ji, j)| (j.name.clone(), ji))
+                .collect(),
+            joints,
+            order,
+        })
+    }
+    pub fn joint_count(&self) -> usize {
+        self.joints.len()
+    }
+    pub fn joint_name(&self, joint: usize) -> &str {
+        &self.joints[joint].name
+    }
+    pub fn joint_index(&self, name: &str) -> Option<usize> {
+        self.names.get(name).copied()
+    }
+    pub fn joint_parent(&self, joint: usize) -> Option<usize> {
+        self.joints[joint].parent
+    }
+    pub fn joint_children(&self, joint: usize) -> &[usize] {
+        &self.joints[joint].children
     }
     pub fn reset(&self, bones: &mut [Bone]) {
-        for (j, b) in self.joints.iter().zip(bones.iter_mut()) {
-            b.translation = j.translation.into();
-            b.rotation = j.rotation.into();
+        for (ji, b) in bones.iter_mut().enumerate().take(self.joints.len()) {
+            *b = self.rest(ji);
+        }
+    }
+    /// `joint`'s binding pose relative to its parent.
+    pub fn rest(&self, joint: usize) -> Bone {
+        let j = &self.joints[joint];
+        Bone {
+            translation: j.translation.into(),
+            rotation: j.rotation.into(),
+            scale: (j.scale.x + j.scale.y + j.scale.z) / 3.0,
+        }
+    }
+    /// Every joint's transform relative to the rig's root under the local pose
+    /// `local`, as left by `Anim::sample_local` or `Animator::pose_local`.
+    pub fn model_transforms(&self, local: &[Bone]) -> Vec<Mat4> {
+        let mut frames = vec![cgmath::Decomposed::one(); self.joints.len()];
+        for &ji in self.order.iter() {
+            frames[ji] = match self.joints[ji].parent {
+                Some(pi) => frames[pi] * local[ji].decomposed(),
+                None => local[ji].decomposed(),
+            };
+        }
+        frames.into_iter().map(Mat4::from).collect()
+    }
+    /// The transform of the joint named `joint` relative to the rig's root under
+    /// the local pose `local`, for attaching things to it.
+    pub fn socket(&self, local: &[Bone], joint: &str) -> Option<Mat4> {
+        let ji = self.joint_index(joint)?;
+        let mut frame = local[ji].decomposed();
+        let mut parent = self.joints[ji].parent;
+        while let Some(pi) = parent {
+            frame = local[pi].decomposed() * frame;
+            parent = self.joints[pi].parent;
+        }
+        Some(Mat4::from(frame))
+    }
+    /// Like `socket`, but from bones already skinned by `skin`, `Anim::sample`
+    /// or `Animator::pose`.
+    pub fn socket_skinned(&self, skinned: &[Bone], joint: &str) -> Option<Mat4> {
+        let ji = self.joint_index(joint)?;
+        // Skinning folded the inverse bind matrix in; take it back out
+        let bind = self.ibms[ji].invert()?;
+        Some(Mat4::from(skinned[ji].decomposed()) * bind)
+    }
+    // Where `joint`'s parent sits relative to the rig's root when all its
+    // ancestors are at rest
+    pub(crate) fn parent_rest_frame(&self, joint: usize) -> cgmath::Decomposed<Vec3, Quat> {
+        let mut frame = cgmath::Decomposed::one();
+        let mut parent = self.joints[joint].parent;
+        while let Some(pi) = parent {
+            frame = self.rest(pi).decomposed() * frame;
+            parent = self.joints[pi].parent;
         }
+        frame
     }
 }
 
-pub struct Anim {
-    trans_targets: Vec<u8>,
-    trans_keys: Vec<Vec3>,
-    rot_targets: Vec<u8>,
-    rot_keys: Vec<Quat>,
-    timings: Vec<f32>,
+// Split a matrix into translation, rotation and uniform scale.  Mirrored or
+// flattened matrices can't be split that way; shear and non-uniform scale
+// can't be kept, so they're averaged away with a warning.
+fn decompose(m: Mat4) -> Result<cgmath::Decomposed<Vec3, Quat>, &'static str> {
+    let rotn = Mat3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate());
+    let det = rotn.determinant();
+    if det <= 0.0 || !det.is_finite() {
+        return Err("is mirrored or has zero scale");
+    }
+    // pull the (uniform) scale out so what's left is a pure rotation
+    let scale = det.cbrt();
+    let lengths = [rotn.x.magnitude(), rotn.y.magnitude(), rotn.z.magnitude()];
+    if lengths.iter().any(|l| (l - scale).abs() > 1e-3 * scale) {
+        eprintln!(
+            "Scale {:?} isn't uniform; skinning will use {} on every axis",
+            lengths, scale
+        );
+    }
+    Ok(cgmath::Decomposed {
+        scale,
+        rot: Quat::from(rotn / scale),
+        disp: m.w.truncate(),
+    })
+}
+
+/// Joint names in an animation mapped to joint names in a rig, for playing
+/// clips on skeletons that name their joints differently.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct RetargetMap {
+    /// Animation joint name to rig joint name; unlisted joints keep their names
+    pub names: HashMap<String, String>,
+    /// Translation keys only suit skeletons with the same proportions, so
+    /// retargeted clips drive the root joints' translation alone unless this is set
+    #[serde(default)]
+    pub keep_translations: bool,
+}
+
+impl RetargetMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with(mut self, anim_joint: impl Into<String>, rig_joint: impl Into<String>) -> Self {
+        self.names.insert(anim_joint.into(), rig_joint.into());
+        self
+    }
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+    fn rig_name<'a>(&'a self, anim_joint: &'a str) -> &'a str {
+        self.names
+            .get(anim_joint)
+            .map(|n| n.as_str())
+            .unwrap_or(anim_joint)
+    }
+}
+
+/// Which rig joint each of an animation's tracks drives; `None` for tracks
+/// whose joint the rig doesn't have.  Make one with `Anim::bind` and reuse it every frame.
+#[derive(Clone, Debug)]
+pub struct AnimBinding {
+    joints: Vec<Option<usize>>,
+}
+
+impl AnimBinding {
+    /// True if every track in the animation found a joint to drive.
+    pub fn is_complete(&self) -> bool {
+        self.joints.iter().all(|j| j.is_some())
+    }
+    /// The rig joints this binding drives.
+    pub fn joints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.joints.iter().filter_map(|j| *j)
+    }
+}
+
+/// How a track gets from one keyframe to the next, as in glTF samplers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    /// Hermite spline; each keyframe stores an in-tangent, a value and an out-tangent
+    CubicSpline,
+}
+
+enum Keys {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+// One animated property of one joint, with its own keyframe times
+struct Track {
+    target: usize,
+    interpolation: Interpolation,
+    times: Vec<f32>,
+    keys: Keys,
+}
+
+// Quaternions q and -q are the same rotation; blend the short way round
+pub(crate) fn nlerp_shortest(from: Quat, to: Quat, amount: f32) -> Quat {
+    let to = if from.dot(to) < 0.0 { -to } else { to };
+    from.nlerp(to, amount)
+}
+
+fn hermite<T>(v0: T, b0: T, v1: T, a1: T, dt: f32, s: f32) -> T
+where
+    T: std::ops::Mul<f32, Output = T> + std::ops::Add<Output = T>,
+{
+    let s2 = s * s;
+    let s3 = s2 * s;
+    v0 * (2.0 * s3 - 3.0 * s2 + 1.0)
+        + b0 * ((s3 - 2.0 * s2 + s) * dt)
+        + v1 * (-2.0 * s3 + 3.0 * s2)
+        + a1 * ((s3 - s2) * dt)
+}
+
+// The keyframe before t and how far along we are towards the next one
+fn locate(times: &[f32], t: f32) -> (usize, usize, f32, f32) {
+    let last = times.len() - 1;
+    if t <= times[0] {
+        return (0, 0, 0.0, 0.0);
+    }
+    if t >= times[last] {
+        return (last, last, 0.0, 0.0);
+    }
+    // times[0] < t < times[last] here, so the last key at or before t is in
+    // 0..last.  Searching for it rather than any key equal to t means two keys
+    // at the same time, which make a jump, always give the later one.
+    let k = match times.binary_search_by(|key| {
+        if *key <= t {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    }) {
+        Ok(k) | Err(k) => k - 1,
+    };
+    let dt = times[k + 1] - times[k];
+    (k, k + 1, (t - times[k]) / dt, dt)
+}
+
+// Like `locate`, for times that only go forwards: `cursor` remembers the key
+// the last time was at, so a run of times walks the keys once instead of
+// searching them every time.  Start it at 0.
+fn locate_from(times: &[f32], t: f32, cursor: &mut usize) -> (usize, usize, f32, f32) {
+    let last = times.len() - 1;
+    if t <= times[0] {
+        return (0, 0, 0.0, 0.0);
+    }
+    if t >= times[last] {
+        *cursor = last;
+        return (last, last, 0.0, 0.0);
+    }
+    while times[*cursor + 1] <= t {
+        *cursor += 1;
+    }
+    let k = *cursor;
+    let dt = times[k + 1] - times[k];
+    (k, k + 1, (t - times[k]) / dt, dt)
+}
+
+impl Track {
+    fn sample_vec3(&self, keys: &[Vec3], (k0, k1, s, dt): (usize, usize, f32, f32)) -> Vec3 {
+        match self.interpolation {
+            Interpolation::Step => keys[k0],
+            Interpolation::Linear => keys[k0].lerp(keys[k1], s),
+            Interpolation::CubicSpline => {
+                if k0 == k1 {
+                    return keys[k0 * 3 + 1];
+                }
+                hermite(
+                    keys[k0 * 3 + 1],
+                    keys[k0 * 3 + 2],
+                    keys[k1 * 3 + 1],
+                    keys[k1 * 3],
+                    dt,
+                    s,
+                )
+            }
+        }
+    }
+    fn sample_quat(&self, keys: &[Quat], (k0, k1, s, dt): (usize, usize, f32, f32)) -> Quat {
+        match self.interpolation {
+            Interpolation::Step => keys[k0],
+            Interpolation::Linear => nlerp_shortest(keys[k0], keys[k1], s),
+            Interpolation::CubicSpline => {
+                if k0 == k1 {
+                    return keys[k0 * 3 + 1].normalize();
+                }
+                hermite(
+                    keys[k0 * 3 + 1],
+                    keys[k0 * 3 + 2],
+                    keys[k1 * 3 + 1],
+                    keys[k1 * 3],
+                    dt,
+                    s,
+                )
+                .normalize()
+            }
+        }
+    }
+    fn apply(&self, t: f32, bone: &mut Bone) {
+        self.apply_at(locate(&self.times, t), bone);
+    }
+    // Set `bone` from the keys `at`, as found by `locate`
+    fn apply_at(&self, at: (usize, usize, f32, f32), bone: &mut Bone) {
+        match &self.keys {
+            Keys::Translation(keys) => bone.translation = self.sample_vec3(keys, at).into(),
+            Keys::Rotation(keys) => bone.rotation = self.sample_quat(keys, at).into(),
+            Keys::Scale(keys) => {
+                let sc = self.sample_vec3(keys, at);
+                bone.scale = (sc.x + sc.y + sc.z) / 3.0;
+            }
+        }
+    }
 }
 
-fn transpose_rowcol<T: Copy + cgmath::Zero>(inp: Vec<T>, num_rows: usize) -> Vec<T> {
-    if num_rows == 0 {
-        return inp;
-    }
-    let num_cols = inp.len() / num_rows;
-    let mut outp = vec![T::zero(); inp.len()];
-    // From https://docs.rs/transpose/0.2.1/src/transpose/out_of_place.rs.html#17-26
-    unsafe {
-        for y in 0..num_rows {
-            for x in 0..num_cols {
-                let inp_idx = x + y * num_cols;
-                let outp_idx = y + x * num_rows;
-                *outp.get_unchecked_mut(outp_idx) = *inp.get_unchecked(inp_idx);
+// Morph target weights over time for the mesh on one node
+struct MorphTrack {
+    target: String,
+    interpolation: Interpolation,
+    times: Vec<f32>,
+    // `count` weights per keyframe, or three times that for cubic splines
+    weights: Vec<f32>,
+    count: usize,
+}
+
+impl MorphTrack {
+    fn apply(&self, t: f32, weights: &mut [f32]) {
+        let (k0, k1, s, dt) = locate(&self.times, t);
+        let n = self.count;
+        for (i, w) in weights.iter_mut().enumerate().take(n) {
+            *w = match self.interpolation {
+                Interpolation::Step => self.weights[k0 * n + i],
+                Interpolation::Linear => {
+                    let (w0, w1) = (self.weights[k0 * n + i], self.weights[k1 * n + i]);
+                    w0 + (w1 - w0) * s
+                }
+                // Keyframes hold all in-tangents, then all values, then all out-tangents
+                Interpolation::CubicSpline if k0 == k1 => self.weights[(k0 * 3 + 1) * n + i],
+                Interpolation::CubicSpline => hermite(
+                    self.weights[(k0 * 3 + 1) * n + i],
+                    self.weights[(k0 * 3 + 2) * n + i],
+                    self.weights[(k1 * 3 + 1) * n + i],
+                    self.weights[(k1 * 3) * n + i],
+                    dt,
+                    s,
+                ),
+            };
+        }
+    }
+}
+
+/// What happens when playback runs off the end of a clip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayMode {
+    /// Start over from the beginning
+    Loop,
+    /// Play through once and stop
+    Once,
+    /// Play through once and hold the last frame
+    Clamp,
+    /// Play backwards to the beginning, then forwards again
+    PingPong,
+}
+
+impl Default for PlayMode {
+    fn default() -> Self {
+        PlayMode::Loop
+    }
+}
+
+impl PlayMode {
+    /// Where in a clip of length `duration` playback is after `t` seconds.
+    pub fn clip_time(self, t: f32, duration: f32) -> f32 {
+        let t = t.max(0.0);
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            PlayMode::Loop => t % duration,
+            PlayMode::Once | PlayMode::Clamp => t.min(duration),
+            PlayMode::PingPong => {
+                let t = t % (2.0 * duration);
+                if t > duration {
+                    2.0 * duration - t
+                } else {
+                    t
+                }
+            }
+        }
+    }
+    /// How many times playback from `from` to `to` seconds passes time `at` in the clip.
+    pub fn crossings(self, from: f32, to: f32, at: f32, duration: f32) -> usize {
+        if duration <= 0.0 || to <= from {
+            return 0;
+        }
+        // Times `at + k * period` in [from, to)
+        let count = |at: f32, period: f32| {
+            let first = ((from - at) / period).ceil();
+            let last = ((to - at) / period).ceil();
+            (last - first).max(0.0) as usize
+        };
+        match self {
+            PlayMode::Loop => count(at, duration),
+            PlayMode::Once | PlayMode::Clamp => {
+                let ended = from < duration && to >= duration;
+                let hit = (from <= at && at < to) || (ended && at == duration);
+                if hit && at <= duration {
+                    1
+                } else {
+                    0
+                }
+            }
+            PlayMode::PingPong => {
+                let forward = count(at, 2.0 * duration);
+                let back = if at > 0.0 && at < duration {
+                    count(2.0 * duration - at, 2.0 * duration)
+                } else {
+                    0
+                };
+                forward + back
             }
         }
     }
-    outp
+}
+
+pub struct Anim {
+    // Joint names, so clips can play on any rig with matching joints
+    targets: Vec<String>,
+    tracks: Vec<Track>,
+    morph_tracks: Vec<MorphTrack>,
+    duration: f32,
 }
 
 impl Anim {
@@ -114,156 +563,227 @@ impl Anim {
         _g: &gltf::Document,
         bufs: &[gltf::buffer::Data],
         anim: gltf::Animation,
-        rig: &Rig,
-    ) -> Self {
-        let timings: Vec<_> = anim
-            .channels()
-            .next()
-            .unwrap()
-            .reader(|b| Some(&bufs[b.index()]))
-            .read_inputs()
-            .unwrap()
-            .collect();
-        let mut trans_targets = vec![];
-        let mut trans_keys_by_tgt = vec![];
-        let mut rot_targets = vec![];
-        let mut rot_keys_by_tgt = vec![];
+    ) -> Result<Self, AssetErrorKind> {
+        use gltf::animation::util::ReadOutputs;
+        let invalid = |why: &str| AssetErrorKind::Invalid(format!("animation {}", why));
+        let mut targets: Vec<String> = vec![];
+        let mut tracks = vec![];
+        let mut morph_tracks = vec![];
         for c in anim.channels() {
             let reader = c.reader(|b| Some(&bufs[b.index()]));
-            let c_timings: Vec<_> = reader.read_inputs().unwrap().collect();
-            assert_eq!(c_timings.len(), timings.len());
-            let tgt = c.target().node().index();
-            let prop = c.target().property();
-            let tgt = rig.nodes_to_joints[&tgt];
-            match prop {
-                gltf::animation::Property::Translation => trans_targets.push(tgt as u8),
-                gltf::animation::Property::Rotation => rot_targets.push(tgt as u8),
-                _ => panic!("Unsupported anim target"),
+            let times: Vec<_> = reader
+                .read_inputs()
+                .ok_or_else(|| invalid("has no keyframe times"))?
+                .collect();
+            if times.is_empty() {
+                return Err(invalid("has a channel with no keyframes"));
+            }
+            let interpolation = match c.sampler().interpolation() {
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
             };
-            match reader.read_outputs().unwrap() {
-                gltf::animation::util::ReadOutputs::Translations(trs) => {
-                    assert_eq!(prop, gltf::animation::Property::Translation);
-                    assert_eq!(trs.len(), timings.len());
-                    // dbg!("TKEY",trs.len());
-                    trans_keys_by_tgt.extend(trs.map(|tr| Vec3::from(tr)));
+            let per_key = if interpolation == Interpolation::CubicSpline {
+                3
+            } else {
+                1
+            };
+            let keys = match reader
+                .read_outputs()
+                .ok_or_else(|| invalid("has no keyframe values"))?
+            {
+                ReadOutputs::Translations(trs) => Keys::Translation(trs.map(Vec3::from).collect()),
+                // into_f32 also unpacks normalized-integer rotations
+                ReadOutputs::Rotations(rots) => {
+                    Keys::Rotation(rots.into_f32().map(Quat::from).collect())
                 }
-                gltf::animation::util::ReadOutputs::Rotations(rots) => {
-                    assert_eq!(prop, gltf::animation::Property::Rotation);
-                    match rots {
-                        gltf::animation::util::Rotations::F32(quadruples) => {
-                            assert_eq!(quadruples.len(), timings.len());
-                            // dbg!("RKEY",quadruples.len());
-                            rot_keys_by_tgt.extend(quadruples.map(|q| Quat::from(q)))
-                        }
-                        _ => panic!("Unsupported rot format"),
+                ReadOutputs::Scales(scs) => Keys::Scale(scs.map(Vec3::from).collect()),
+                // Morph target weights don't drive joints, so they get tracks of their own
+                ReadOutputs::MorphTargetWeights(ws) => {
+                    let weights: Vec<f32> = ws.into_f32().collect();
+                    let count = weights.len() / (times.len() * per_key);
+                    if count == 0 || weights.len() != count * times.len() * per_key {
+                        return Err(invalid("has a morph weight count mismatch"));
                     }
+                    morph_tracks.push(MorphTrack {
+                        target: node_name(&c.target().node()),
+                        interpolation,
+                        times,
+                        weights,
+                        count,
+                    });
+                    continue;
                 }
-                _ => panic!("Unsupported anim output"),
+            };
+            let count = match &keys {
+                Keys::Translation(k) | Keys::Scale(k) => k.len(),
+                Keys::Rotation(k) => k.len(),
+            };
+            if count != times.len() * per_key {
+                return Err(invalid("has a keyframe count mismatch"));
             }
+            let name = node_name(&c.target().node());
+            let target = match targets.iter().position(|t| *t == name) {
+                Some(tgt) => tgt,
+                None => {
+                    targets.push(name);
+                    targets.len() - 1
+                }
+            };
+            tracks.push(Track {
+                target,
+                interpolation,
+                times,
+                keys,
+            });
         }
-        let tklen = trans_keys_by_tgt.len();
-        let rklen = rot_keys_by_tgt.len();
-        let trans_keys = transpose_rowcol(trans_keys_by_tgt, trans_targets.len());
-        let rot_keys = transpose_rowcol(rot_keys_by_tgt, rot_targets.len());
-        assert_eq!(trans_keys.len(), tklen);
-        assert_eq!(rot_keys.len(), rklen);
-        Self {
-            timings,
-            trans_keys,
-            rot_keys,
-            trans_targets,
-            rot_targets,
-        }
+        let duration = tracks
+            .iter()
+            .map(|tr| &tr.times)
+            .chain(morph_tracks.iter().map(|tr| &tr.times))
+            .map(|times| *times.last().unwrap())
+            .fold(0.0, f32::max);
+        Ok(Self {
+            targets,
+            tracks,
+            morph_tracks,
+            duration,
+        })
     }
     pub fn duration(&self) -> f32 {
-        *self.timings.last().unwrap_or(&0.0)
-    }
-    pub fn sample(&self, mut t: f32, rig: &Rig, bones: &mut [Bone]) {
-        assert!(self.duration() > 0.0);
-        assert!(t >= 0.0);
-        // TODO maybe not the best place for this?
-        while t >= self.duration() {
-            t -= self.duration();
+        self.duration
+    }
+    /// Names of the joints this animation drives.
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+    /// Names of the nodes whose meshes' morph target weights this animation drives.
+    pub fn morph_targets(&self) -> impl Iterator<Item = &str> + '_ {
+        self.morph_tracks.iter().map(|tr| tr.target.as_str())
+    }
+    /// Write the morph target weights at time `t` for the mesh on node `node`
+    /// into `weights`.  Returns false, leaving `weights` alone, if this
+    /// animation doesn't drive that node's weights.
+    pub fn sample_weights(&self, t: f32, node: &str, weights: &mut [f32]) -> bool {
+        match self.morph_tracks.iter().find(|tr| tr.target == node) {
+            Some(track) => {
+                track.apply(t, weights);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Match this animation's tracks to `rig`'s joints by name, renaming them
+    /// through `map` first if the skeletons name their joints differently.
+    pub fn bind(&self, rig: &Rig, map: Option<&RetargetMap>) -> AnimBinding {
+        AnimBinding {
+            joints: self
+                .tracks
+                .iter()
+                .map(|track| {
+                    let name = self.targets[track.target].as_str();
+                    let ji = rig.joint_index(map.map_or(name, |m| m.rig_name(name)))?;
+                    match (&track.keys, map) {
+                        (Keys::Translation(_), Some(m))
+                            if !m.keep_translations && rig.joint_parent(ji).is_some() =>
+                        {
+                            None
+                        }
+                        _ => Some(ji),
+                    }
+                })
+                .collect(),
         }
-        let t = self.timings.last().unwrap().min(t);
-        let kidx = self
-            .timings
+    }
+    /// Sample onto a rig whose joints share this animation's names, looping.
+    pub fn sample(&self, t: f32, rig: &Rig, bones: &mut [Bone]) {
+        self.sample_bound(t, rig, &self.bind(rig, None), bones);
+    }
+    /// Sample onto a rig through a binding from `bind`, looping.
+    pub fn sample_bound(&self, t: f32, rig: &Rig, binding: &AnimBinding, bones: &mut [Bone]) {
+        let t = PlayMode::Loop.clip_time(t, self.duration);
+        self.sample_local(t, binding, bones);
+        rig.skin(bones);
+    }
+    /// Sample onto many characters sharing `rig` at once, one pose per entry in
+    /// `times`, looping, and skin them.  `bones` holds the poses back to back,
+    /// `rig.joint_count()` bones each.  Gives the same poses as calling
+    /// `sample_bound` for each, but visits the crowd in time order so each
+    /// track's keys are walked once instead of searched for every character.
+    /// benches/anim_sample.rs compares the two.
+    pub fn sample_batch(
+        &self,
+        times: &[f32],
+        rig: &Rig,
+        binding: &AnimBinding,
+        bones: &mut [Bone],
+    ) {
+        let stride = rig.joint_count();
+        if stride == 0 {
+            return;
+        }
+        let count = times.len().min(bones.len() / stride);
+        let times: Vec<f32> = times[..count]
             .iter()
-            .zip(self.timings[1..].iter())
-            .position(|(t0, t1)| t >= *t0 && t <= *t1)
-            .unwrap();
-        let t0 = self.timings[kidx];
-        let t1 = self.timings[kidx + 1];
-        let tr = (t - t0) / (t1 - t0);
-        //let key_count = self.timings.len();
-        let ttgt_count = self.trans_targets.len();
-        let rtgt_count = self.rot_targets.len();
-        // println!("Anim: {} t, {} r, {} keyframes ({}/{})", ttgt_count, rtgt_count, self.timings.len(), self.trans_keys.len(), self.rot_keys.len());
-        if ttgt_count > 0 {
-            // there are trans_targets trans targets per keyframe
-            // there are trans_targets * timings trans_keys total
-            let tfrom = &self.trans_keys[(ttgt_count * kidx)..(ttgt_count * (kidx + 1))];
-            let tto = &self.trans_keys[(ttgt_count * (kidx + 1))..(ttgt_count * (kidx + 2))];
-            for ((tgt, from), to) in self.trans_targets.iter().zip(tfrom.iter()).zip(tto.iter()) {
-                let j = &rig.joints[*tgt as usize];
-                bones[*tgt as usize].translation = (from.lerp(*to, tr)).into();
+            .map(|t| PlayMode::Loop.clip_time(*t, self.duration))
+            .collect();
+        let mut order: Vec<usize> = (0..count).collect();
+        order.sort_by(|a, b| times[*a].partial_cmp(&times[*b]).unwrap_or(Ordering::Equal));
+        for (track, joint) in self.tracks.iter().zip(binding.joints.iter()) {
+            if let Some(ji) = joint {
+                let mut cursor = 0;
+                for &i in order.iter() {
+                    let at = locate_from(&track.times, times[i], &mut cursor);
+                    track.apply_at(at, &mut bones[i * stride + ji]);
+                }
+            }
+        }
+        for pose in bones.chunks_exact_mut(stride).take(count) {
+            rig.skin(pose);
+        }
+    }
+    /// Write the joint-local pose at time `t` into `bones`, leaving joints the
+    /// animation doesn't drive alone.  Times outside the clip hold its first or last key.
+    pub fn sample_local(&self, t: f32, binding: &AnimBinding, bones: &mut [Bone]) {
+        for (track, joint) in self.tracks.iter().zip(binding.joints.iter()) {
+            if let Some(ji) = joint {
+                track.apply(t, &mut bones[*ji]);
             }
         }
-        // there are rot_targets rot targets per keyframe
-        // there are rot_targets * timings trans_keys total
-        if rtgt_count > 0 {
-            let rfrom = &self.rot_keys[(rtgt_count * kidx)..(rtgt_count * (kidx + 1))];
-            let rto = &self.rot_keys[(rtgt_count * (kidx + 1))..(rtgt_count * (kidx + 2))];
-            for ((tgt, from), to) in self.rot_targets.iter().zip(rfrom.iter()).zip(rto.iter()) {
-                let j = &rig.joints[*tgt as usize];
-                bones[*tgt as usize].rotation = (from.nlerp(*to, tr)).into();
+    }
+    /// Like `sample_local`, for the tracks driving `joint` alone.
+    pub fn sample_joint(&self, t: f32, binding: &AnimBinding, joint: usize, bone: &mut Bone) {
+        for (track, ji) in self.tracks.iter().zip(binding.joints.iter()) {
+            if *ji == Some(joint) {
+                track.apply(t, bone);
             }
         }
+    }
+}
 
+impl Rig {
+    /// Turn a joint-local pose into skinning transforms for `render_anim`.
+    pub fn skin(&self, bones: &mut [Bone]) {
         // right now all bones are set in joint-local terms.
         // we need to go from top to bottom to fix that...
-        for (ji, j) in rig.joints.iter().enumerate() {
-            let b = bones[ji];
+        for &ji in self.order.iter() {
             // transform all direct child bones by this bone's transformation.
-            let br = Quat::from(b.rotation);
-            let bt = Vec3::from(b.translation);
-            let btrans = cgmath::Decomposed {
-                scale: 1.0,
-                rot: br,
-                disp: bt,
-            };
-            for &ci in j.children.iter() {
-                if ci == 255 {
-                    break;
-                }
-                let b2 = &mut bones[ci as usize];
-                let b2trans = btrans
-                    * cgmath::Decomposed {
-                        scale: 1.0,
-                        rot: b2.rotation.into(),
-                        disp: b2.translation.into(),
-                    };
+            let btrans = bones[ji].decomposed();
+            for &ci in self.joints[ji].children.iter() {
+                let b2 = &mut bones[ci];
+                let b2trans = btrans * b2.decomposed();
                 // augment b2 translation: include rotate-move from b to b2
                 b2.translation = b2trans.disp.into();
                 b2.rotation = b2trans.rot.into();
+                b2.scale = b2trans.scale;
             }
             // but then we need to multiply by the inverse bind matrix to
             // turn this bone into a "change in vertex translations"
-            let ibm = rig.ibms[ji];
-            let post_ibm: Mat4 = Mat4::from(cgmath::Decomposed {
-                scale: 1.0_f32,
-                rot: Quat::from(b.rotation),
-                disp: b.translation.into(),
-            }) * ibm;
-            let transl = post_ibm.w.truncate();
-            let rotn = Mat3::from_cols(
-                post_ibm.x.truncate(),
-                post_ibm.y.truncate(),
-                post_ibm.z.truncate(),
-            );
+            let post_ibm = btrans * self.ibm_parts[ji];
             let b = &mut bones[ji];
-            b.translation = transl.into();
-            b.rotation = Quat::from(rotn).into();
+            b.translation = post_ibm.disp.into();
+            b.rotation = post_ibm.rot.normalize().into();
+            b.scale = post_ibm.scale;
         }
     }
 }
@@ -275,9 +795,22 @@ where
     fn draw_model_skinned(
         &mut self,
         model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+        bones: &'b wgpu::BindGroup,
+        pose_offsets: &[wgpu::DynamicOffset],
+    );
+    #[allow(clippy::too_many_arguments)]
+    fn draw_mesh_skinned(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        instances: Range<u32>,
         uniforms: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
         bones: &'b wgpu::BindGroup,
+        pose_offsets: &[wgpu::DynamicOffset],
     );
 }
 
@@ -288,13 +821,141 @@ where
     fn draw_model_skinned(
         &mut self,
         model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+        bones: &'b wgpu::BindGroup,
+        pose_offsets: &[wgpu::DynamicOffset],
+    ) {
+        self.set_bind_group(3, bones, pose_offsets);
+        self.draw_model_instanced(model, instances, uniforms, light);
+    }
+    fn draw_mesh_skinned(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        instances: Range<u32>,
         uniforms: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
         bones: &'b wgpu::BindGroup,
+        pose_offsets: &[wgpu::DynamicOffset],
     ) {
-        self.set_bind_group(3, &bones, &[]);
-        self.draw_model_instanced(model, 0..1, uniforms, light);
+        self.set_bind_group(3, bones, pose_offsets);
+        self.draw_mesh_instanced(mesh, material, instances, uniforms, light);
     }
 }
 
 pub struct State {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rig_skips_non_joint_children() {
+        // A sword mesh held by the hand, and a light on the head
+        let json = r#"{
+            "asset": { "version": "2.0" },
+            "nodes": [
+                { "name": "spine", "children": [1, 2] },
+                { "name": "hand", "translation": [1, 0, 0], "children": [3] },
+                { "name": "head", "translation": [0, 1, 0], "children": [4] },
+                { "name": "sword", "mesh": 0 },
+                { "name": "lamp" }
+            ],
+            "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }],
+            "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 1, "type": "VEC3",
+                            "min": [0, 0, 0], "max": [0, 0, 0] }],
+            "bufferViews": [{ "buffer": 0, "byteLength": 12 }],
+            "buffers": [{ "byteLength": 12 }],
+            "skins": [{ "joints": [0, 1, 2] }]
+        }"#;
+        let g = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+        let rig = Rig::from_gltf(&g, &[], g.skins().next().unwrap()).unwrap();
+        assert_eq!(rig.joint_count(), 3);
+        let spine = rig.joint_index("spine").unwrap();
+        let hand = rig.joint_index("hand").unwrap();
+        assert_eq!(rig.joint_children(spine).len(), 2);
+        assert!(rig.joint_children(hand).is_empty());
+        assert_eq!(rig.joint_parent(hand), Some(spine));
+    }
+
+    #[test]
+    fn locate_between_keys() {
+        let times = [0.0, 1.0, 3.0];
+        assert_eq!(locate(&times, 0.5), (0, 1, 0.5, 1.0));
+        assert_eq!(locate(&times, 2.0), (1, 2, 0.5, 2.0));
+        // Exactly on a key starts the segment after it
+        assert_eq!(locate(&times, 1.0), (1, 2, 0.0, 2.0));
+    }
+
+    #[test]
+    fn locate_clamps_outside_keys() {
+        let times = [1.0, 2.0];
+        assert_eq!(locate(&times, 0.0), (0, 0, 0.0, 0.0));
+        assert_eq!(locate(&times, 5.0), (1, 1, 0.0, 0.0));
+        assert_eq!(locate(&[4.0], 4.5), (0, 0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn locate_takes_later_of_duplicate_keys() {
+        let times = [0.0, 1.0, 1.0, 2.0];
+        assert_eq!(locate(&times, 1.0), (2, 3, 0.0, 1.0));
+        assert_eq!(locate(&times, 0.5), (0, 1, 0.5, 1.0));
+        assert_eq!(locate(&times, 1.5), (2, 3, 0.5, 1.0));
+    }
+
+    #[test]
+    fn locate_from_matches_locate() {
+        let times = [0.0, 0.5, 1.0, 1.0, 2.0, 3.5];
+        let mut cursor = 0;
+        for i in 0..45 {
+            let t = i as f32 * 0.1 - 0.3;
+            assert_eq!(
+                locate_from(&times, t, &mut cursor),
+                locate(&times, t),
+                "at {}",
+                t
+            );
+        }
+    }
+
+    #[test]
+    fn clip_time_per_mode() {
+        assert_eq!(PlayMode::Loop.clip_time(2.5, 2.0), 0.5);
+        assert_eq!(PlayMode::Once.clip_time(2.5, 2.0), 2.0);
+        assert_eq!(PlayMode::Clamp.clip_time(-1.0, 2.0), 0.0);
+        assert_eq!(PlayMode::PingPong.clip_time(2.5, 2.0), 1.5);
+        assert_eq!(PlayMode::PingPong.clip_time(4.5, 2.0), 0.5);
+        assert_eq!(PlayMode::Loop.clip_time(3.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn crossings_loop() {
+        // An event at 0.5 in a 2 second clip, over three and a bit loops
+        assert_eq!(PlayMode::Loop.crossings(0.0, 6.6, 0.5, 2.0), 4);
+        assert_eq!(PlayMode::Loop.crossings(0.6, 2.4, 0.5, 2.0), 0);
+        // Half-open: passing `from` counts, reaching `to` doesn't
+        assert_eq!(PlayMode::Loop.crossings(0.5, 1.0, 0.5, 2.0), 1);
+        assert_eq!(PlayMode::Loop.crossings(0.0, 0.5, 0.5, 2.0), 0);
+        assert_eq!(PlayMode::Loop.crossings(1.0, 1.0, 0.5, 2.0), 0);
+    }
+
+    #[test]
+    fn crossings_once() {
+        assert_eq!(PlayMode::Once.crossings(0.0, 10.0, 0.5, 2.0), 1);
+        assert_eq!(PlayMode::Once.crossings(3.0, 10.0, 0.5, 2.0), 0);
+        // An event on the last frame fires when playback runs off the end
+        assert_eq!(PlayMode::Clamp.crossings(1.5, 2.5, 2.0, 2.0), 1);
+        assert_eq!(PlayMode::Clamp.crossings(2.5, 3.5, 2.0, 2.0), 0);
+    }
+
+    #[test]
+    fn crossings_ping_pong() {
+        // Forwards at 0.5, back at 3.5, forwards again at 4.5
+        assert_eq!(PlayMode::PingPong.crossings(0.0, 5.0, 0.5, 2.0), 3);
+        // The turning points are only passed once per trip
+        assert_eq!(PlayMode::PingPong.crossings(0.0, 5.0, 2.0, 2.0), 1);
+        assert_eq!(PlayMode::PingPong.crossings(0.0, 5.0, 0.0, 2.0), 2);
+    }
+}
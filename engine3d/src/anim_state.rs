@@ -0,0 +1,315 @@
+use crate::anim::{Bone, PlayMode};
+use crate::animator::{AnimEvent, Animator};
+use crate::assets::{AnimRef, Assets, RigRef};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A state machine as written in JSON, e.g.
+///
+/// ```json
+/// {
+///   "initial": "idle",
+///   "states": [
+///     { "name": "idle", "clip": "Survey" },
+///     { "name": "walk", "clip": "Walk", "speed": 1.2,
+///       "events": [{ "time": 0.4, "name": "footstep" }] }
+///   ],
+///   "transitions": [
+///     { "from": "idle", "to": "walk", "when": [{ "param": "speed", "op": ">", "value": 0.1 }], "blend": 0.25 },
+///     { "from": "walk", "to": "idle", "when": [{ "param": "speed", "op": "<=", "value": 0.1 }], "blend": 0.25 }
+///   ]
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateMachineDef {
+    pub initial: String,
+    pub states: Vec<StateDef>,
+    #[serde(default)]
+    pub transitions: Vec<TransitionDef>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateDef {
+    pub name: String,
+    /// Name of the animation to play, as found in the loaded scene
+    pub clip: String,
+    #[serde(default = "normal_speed")]
+    pub speed: f32,
+    #[serde(default)]
+    pub mode: PlayMode,
+    /// Markers reported through `AnimStateMachine::events`
+    #[serde(default)]
+    pub events: Vec<EventDef>,
+}
+
+fn normal_speed() -> f32 {
+    1.0
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EventDef {
+    pub time: f32,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransitionDef {
+    /// A state name, or "*" for any state
+    pub from: String,
+    pub to: String,
+    /// All of these must hold for the transition to fire
+    #[serde(default)]
+    pub when: Vec<Condition>,
+    /// Crossfade time in seconds
+    #[serde(default)]
+    pub blend: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Condition {
+    pub param: String,
+    pub op: Compare,
+    /// Booleans compare as 1.0 (true) and 0.0 (false)
+    pub value: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compare {
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+}
+
+impl Condition {
+    fn holds(&self, params: &HashMap<String, f32>) -> bool {
+        // Parameters nobody has set yet count as 0 (false)
+        let v = params.get(&self.param).copied().unwrap_or(0.0);
+        match self.op {
+            Compare::Gt => v > self.value,
+            Compare::Ge => v >= self.value,
+            Compare::Lt => v < self.value,
+            Compare::Le => v <= self.value,
+            Compare::Eq => (v - self.value).abs() < std::f32::EPSILON,
+            Compare::Ne => (v - self.value).abs() >= std::f32::EPSILON,
+        }
+    }
+}
+
+impl StateMachineDef {
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// Runs a `StateMachineDef` for one character: the game sets parameters,
+/// transitions fire when their conditions hold, and the current state's clip
+/// plays through an `Animator`.
+#[derive(Clone, Debug)]
+pub struct AnimStateMachine {
+    def: StateMachineDef,
+    // The clip for each state, in the same order as def.states
+    clips: Vec<AnimRef>,
+    current: usize,
+    params: HashMap<String, f32>,
+    animator: Animator,
+}
+
+impl AnimStateMachine {
+    /// Bind a definition's clip names to animations with `clip`, for example
+    /// `|name| scene.anim(name)`.
+    pub fn new(
+        def: StateMachineDef,
+        clip: impl Fn(&str) -> Option<AnimRef>,
+    ) -> Result<Self, String> {
+        let clips = def
+            .states
+            .iter()
+            .map(|s| {
+                clip(&s.clip).ok_or_else(|| format!("state {:?}: no clip {:?}", s.name, s.clip))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let state = |name: &str| def.states.iter().position(|s| s.name == name);
+        let current =
+            state(&def.initial).ok_or_else(|| format!("no initial state {:?}", def.initial))?;
+        for t in def.transitions.iter() {
+            if t.from != "*" && state(&t.from).is_none() {
+                return Err(format!("transition from unknown state {:?}", t.from));
+            }
+            if state(&t.to).is_none() {
+                return Err(format!("transition to unknown state {:?}", t.to));
+            }
+        }
+        let mut animator = Animator::new();
+        for (s, clip) in def.states.iter().zip(clips.iter()) {
+            for e in s.events.iter() {
+                animator.add_event(*clip, e.time, e.name.clone());
+            }
+        }
+        let mut machine = Self {
+            def,
+            clips,
+            current,
+            params: HashMap::new(),
+            animator,
+        };
+        machine.enter(current, 0.0);
+        Ok(machine)
+    }
+    pub fn set_param(&mut self, name: &str, value: f32) {
+        self.params.insert(name.to_string(), value);
+    }
+    pub fn set_bool(&mut self, name: &str, value: bool) {
+        self.set_param(name, if value { 1.0 } else { 0.0 });
+    }
+    pub fn param(&self, name: &str) -> f32 {
+        self.params.get(name).copied().unwrap_or(0.0)
+    }
+    /// Name of the current state.
+    pub fn state(&self) -> &str {
+        &self.def.states[self.current].name
+    }
+    /// Jump to a state without checking transitions, crossfading over `blend` seconds.
+    pub fn travel(&mut self, state: &str, blend: f32) {
+        if let Some(si) = self.def.states.iter().position(|s| s.name == state) {
+            self.enter(si, blend);
+        }
+    }
+    fn enter(&mut self, si: usize, blend: f32) {
+        let state = &self.def.states[si];
+        let clip = self.clips[si];
+        self.current = si;
+        self.animator.crossfade(clip, blend);
+        self.animator.set_speed(clip, state.speed);
+        self.animator.set_mode(clip, state.mode);
+    }
+    /// Markers and finished clips from the last `update`.
+    pub fn events(&self) -> &[AnimEvent] {
+        self.animator.events()
+    }
+    pub fn animator(&self) -> &Animator {
+        &self.animator
+    }
+    pub fn animator_mut(&mut self) -> &mut Animator {
+        &mut self.animator
+    }
+    /// Take the first transition out of the current state whose conditions
+    /// hold, then advance playback by `dt` seconds.
+    pub fn update(&mut self, assets: &Assets, dt: f32) {
+        let current = self.state();
+        let next = self
+            .def
+            .transitions
+            .iter()
+            .filter(|t| t.from == current || (t.from == "*" && t.to != current))
+            .find(|t| t.when.iter().all(|c| c.holds(&self.params)))
+            .map(|t| (t.to.clone(), t.blend));
+        if let Some((to, blend)) = next {
+            self.travel(&to, blend);
+        }
+        self.animator.update(assets, dt);
+    }
+    /// Pose `rig` for rendering; see `Animator::pose`.
+    pub fn pose(&mut self, assets: &Assets, rig: RigRef, bones: &mut [Bone]) {
+        self.animator.pose(assets, rig, bones);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_with_defaults() {
+        let def = StateMachineDef::from_json(
+            r#"{
+                "initial": "idle",
+                "states": [
+                    { "name": "idle", "clip": "Survey" },
+                    { "name": "jump", "clip": "Jump", "speed": 2.0, "mode": "once",
+                      "events": [{ "time": 0.4, "name": "land" }] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(def.initial, "idle");
+        assert!(def.transitions.is_empty());
+        let (idle, jump) = (&def.states[0], &def.states[1]);
+        assert_eq!(idle.speed, 1.0);
+        assert_eq!(idle.mode, PlayMode::Loop);
+        assert!(idle.events.is_empty());
+        assert_eq!(jump.speed, 2.0);
+        assert_eq!(jump.mode, PlayMode::Once);
+        assert_eq!(jump.events[0].name, "land");
+    }
+
+    #[test]
+    fn parses_the_fox() {
+        let def =
+            StateMachineDef::from_json(include_str!("../../content/khronos/Fox/fox_states.json"))
+                .unwrap();
+        assert_eq!(def.states.len(), 3);
+        let any = def.transitions.iter().find(|t| t.from == "*").unwrap();
+        assert_eq!(any.to, "idle");
+        assert_eq!(any.blend, 0.5);
+        assert_eq!(any.when[0].op, Compare::Le);
+    }
+
+    #[test]
+    fn rejects_bad_json() {
+        // Missing the initial state
+        assert!(StateMachineDef::from_json(r#"{ "states": [] }"#).is_err());
+        let unknown_op = r#"{
+            "initial": "a",
+            "states": [{ "name": "a", "clip": "A" }],
+            "transitions": [{ "from": "a", "to": "a", "when": [{ "param": "x", "op": "=>", "value": 1 }] }]
+        }"#;
+        assert!(StateMachineDef::from_json(unknown_op).is_err());
+    }
+
+    #[test]
+    fn conditions_compare_params() {
+        let when = |op: &str, value: f32| -> Condition {
+            serde_json::from_str(&format!(
+                r#"{{ "param": "speed", "op": "{}", "value": {} }}"#,
+                op, value
+            ))
+            .unwrap()
+        };
+        let mut params = HashMap::new();
+        // Unset parameters are 0
+        assert!(when("==", 0.0).holds(&params));
+        params.insert("speed".to_string(), 1.0);
+        assert!(when(">", 0.5).holds(&params));
+        assert!(!when(">", 1.0).holds(&params));
+        assert!(when(">=", 1.0).holds(&params));
+        assert!(when("<", 2.0).holds(&params));
+        assert!(when("<=", 1.0).holds(&params));
+        assert!(when("!=", 0.0).holds(&params));
+    }
+
+    #[test]
+    fn missing_clip_is_an_error() {
+        let def = StateMachineDef::from_json(
+            r#"{ "initial": "idle", "states": [{ "name": "idle", "clip": "Survey" }] }"#,
+        )
+        .unwrap();
+        let err = AnimStateMachine::new(def, |_| None).unwrap_err();
+        assert!(err.contains("Survey"), "{}", err);
+    }
+}
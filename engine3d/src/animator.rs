@@ -0,0 +1,757 @@
+use crate::anim::{nlerp_shortest, Anim, AnimBinding, Bone, PlayMode, RetargetMap, Rig};
+use crate::assets::{AnimRef, Assets, RigRef};
+use crate::geom::*;
+use std::collections::HashMap;
+
+/// Something that happened during `Animator::update`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnimEvent {
+    /// Playback crossed a marker added with `Animator::add_event`
+    Marker { anim: AnimRef, name: String },
+    /// A `Once` or `Clamp` clip reached its end
+    Finished { anim: AnimRef },
+}
+
+// One animation playing in an animator
+#[derive(Clone, Debug)]
+struct Clip {
+    anim: AnimRef,
+    // Seconds of playback so far, after speed; `time` is where that lands in the clip
+    elapsed: f32,
+    time: f32,
+    speed: f32,
+    mode: PlayMode,
+    weight: f32,
+    // Where the weight is heading and how fast (per second); clips that fade
+    // out to nothing are dropped
+    target: f32,
+    rate: f32,
+    binding: Option<(RigRef, AnimBinding)>,
+}
+
+impl Clip {
+    fn new(anim: AnimRef, weight: f32) -> Self {
+        Self {
+            anim,
+            elapsed: 0.0,
+            time: 0.0,
+            speed: 1.0,
+            mode: PlayMode::Loop,
+            weight,
+            target: weight,
+            rate: 0.0,
+            binding: None,
+        }
+    }
+    fn advance(
+        &mut self,
+        dt: f32,
+        duration: f32,
+        markers: Option<&Vec<(f32, String)>>,
+        events: &mut Vec<AnimEvent>,
+    ) {
+        let from = self.elapsed;
+        self.elapsed += dt * self.speed;
+        self.time = self.mode.clip_time(self.elapsed, duration);
+        for (at, name) in markers.into_iter().flatten() {
+            for _ in 0..self.mode.crossings(from, self.elapsed, *at, duration) {
+                events.push(AnimEvent::Marker {
+                    anim: self.anim,
+                    name: name.clone(),
+                });
+            }
+        }
+        let ends = self.mode == PlayMode::Once || self.mode == PlayMode::Clamp;
+        if ends && from < duration && self.elapsed >= duration {
+            events.push(AnimEvent::Finished { anim: self.anim });
+            if self.mode == PlayMode::Once {
+                self.weight = 0.0;
+                self.target = 0.0;
+            }
+        }
+        if self.weight < self.target {
+            self.weight = (self.weight + self.rate * dt).min(self.target);
+        } else {
+            self.weight = (self.weight - self.rate * dt).max(self.target);
+        }
+    }
+    // Binding looks joints up by name, so only redo it for a new rig
+    fn binding(
+        &mut self,
+        anim: &Anim,
+        rig: RigRef,
+        rig_data: &Rig,
+        retarget: Option<&RetargetMap>,
+    ) -> &AnimBinding {
+        let stale = match &self.binding {
+            Some((r, _)) => *r != rig,
+            None => true,
+        };
+        if stale {
+            self.binding = Some((rig, anim.bind(rig_data, retarget)));
+        }
+        &self.binding.as_ref().unwrap().1
+    }
+}
+
+/// How a layer combines with the layers beneath it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerBlend {
+    /// Replace the pose underneath, fading in by the layer's weight
+    Override,
+    /// Add each clip's motion away from its first frame on top of the pose underneath
+    Additive,
+}
+
+/// Which joints a layer affects, and how strongly, by joint name.
+#[derive(Clone, Debug, Default)]
+pub struct JointMask {
+    // (joint, weight, include descendants); later entries win
+    entries: Vec<(String, f32, bool)>,
+}
+
+impl JointMask {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Affect `joint` and everything below it, e.g. the spine for an upper-body layer.
+    pub fn subtree(mut self, joint: impl Into<String>, weight: f32) -> Self {
+        self.entries.push((joint.into(), weight, true));
+        self
+    }
+    /// Affect `joint` alone.
+    pub fn joint(mut self, joint: impl Into<String>, weight: f32) -> Self {
+        self.entries.push((joint.into(), weight, false));
+        self
+    }
+    /// Per-joint weights for `rig`; joints the mask doesn't mention get 0.
+    pub fn resolve(&self, rig: &Rig) -> Vec<f32> {
+        let mut weights = vec![0.0; rig.joint_count()];
+        for (name, weight, subtree) in self.entries.iter() {
+            let mut stack: Vec<usize> = rig.joint_index(name).into_iter().collect();
+            while let Some(ji) = stack.pop() {
+                weights[ji] = *weight;
+                if *subtree {
+                    stack.extend(rig.joint_children(ji));
+                }
+            }
+        }
+        weights
+    }
+}
+
+/// A set of weighted clips blended together and then laid over the layers beneath.
+#[derive(Clone, Debug)]
+pub struct AnimLayer {
+    clips: Vec<Clip>,
+    blend: LayerBlend,
+    weight: f32,
+    mask: Option<JointMask>,
+    // The mask resolved for the last rig posed
+    mask_weights: Option<(RigRef, Vec<f32>)>,
+}
+
+impl AnimLayer {
+    fn new(blend: LayerBlend, weight: f32) -> Self {
+        Self {
+            clips: vec![],
+            blend,
+            weight: weight.max(0.0).min(1.0),
+            mask: None,
+            mask_weights: None,
+        }
+    }
+    fn clip_mut(&mut self, anim: AnimRef) -> &mut Clip {
+        match self.clips.iter().position(|c| c.anim == anim) {
+            Some(ci) => &mut self.clips[ci],
+            None => {
+                self.clips.push(Clip::new(anim, 0.0));
+                self.clips.last_mut().unwrap()
+            }
+        }
+    }
+    /// Switch to `anim` right away from its start, dropping everything else.
+    pub fn play(&mut self, anim: AnimRef) {
+        self.clips.retain(|c| c.anim == anim);
+        let clip = self.clip_mut(anim);
+        clip.elapsed = 0.0;
+        clip.time = 0.0;
+        clip.weight = 1.0;
+        clip.target = 1.0;
+        clip.rate = 0.0;
+    }
+    /// Fade `anim` in over `duration` seconds while everything else fades out.
+    pub fn crossfade(&mut self, anim: AnimRef, duration: f32) {
+        if duration <= 0.0 {
+            self.play(anim);
+            return;
+        }
+        for clip in self.clips.iter_mut() {
+            clip.target = 0.0;
+            clip.rate = clip.weight / duration;
+        }
+        let clip = self.clip_mut(anim);
+        clip.target = 1.0;
+        clip.rate = (1.0 - clip.weight) / duration;
+    }
+    /// Play `anim` at a fixed weight alongside whatever else is playing.
+    /// Weights are relative to each other; a weight of 0 stops the clip.
+    pub fn set_weight(&mut self, anim: AnimRef, weight: f32) {
+        if weight <= 0.0 {
+            self.stop(anim);
+            return;
+        }
+        let clip = self.clip_mut(anim);
+        clip.weight = weight;
+        clip.target = weight;
+        clip.rate = 0.0;
+    }
+    pub fn stop(&mut self, anim: AnimRef) {
+        self.clips.retain(|c| c.anim != anim);
+    }
+    /// Stop every clip on this layer.
+    pub fn clear(&mut self) {
+        self.clips.clear();
+    }
+    /// The current weight of `anim`, or `None` if it isn't playing.
+    pub fn weight(&self, anim: AnimRef) -> Option<f32> {
+        self.clips.iter().find(|c| c.anim == anim).map(|c| c.weight)
+    }
+    /// How far into `anim` playback is, or `None` if it isn't playing.
+    pub fn time(&self, anim: AnimRef) -> Option<f32> {
+        self.clips.iter().find(|c| c.anim == anim).map(|c| c.time)
+    }
+    /// Playback rate for `anim` while it plays; 1.0 is normal speed.
+    pub fn set_speed(&mut self, anim: AnimRef, speed: f32) {
+        if let Some(clip) = self.clips.iter_mut().find(|c| c.anim == anim) {
+            clip.speed = speed.max(0.0);
+        }
+    }
+    /// How `anim` behaves at its end while it plays; clips loop by default.
+    pub fn set_mode(&mut self, anim: AnimRef, mode: PlayMode) {
+        if let Some(clip) = self.clips.iter_mut().find(|c| c.anim == anim) {
+            clip.mode = mode;
+        }
+    }
+    pub fn blend(&self) -> LayerBlend {
+        self.blend
+    }
+    /// How much of this layer shows over the ones beneath, from 0 to 1.
+    pub fn set_layer_weight(&mut self, weight: f32) {
+        self.weight = weight.max(0.0).min(1.0);
+    }
+    pub fn layer_weight(&self) -> f32 {
+        self.weight
+    }
+    /// Limit the layer to some joints; `None` lets it affect the whole rig.
+    pub fn set_mask(&mut self, mask: Option<JointMask>) {
+        self.mask = mask;
+        self.mask_weights = None;
+    }
+}
+
+// Reused buffers for posing; kept apart from the layers so both can be
+// borrowed at once
+#[derive(Clone, Debug, Default)]
+struct Scratch {
+    // One clip's pose, and for additive layers its first frame
+    clip: Vec<Bone>,
+    reference: Vec<Bone>,
+    // One layer's blended pose, and which joints its clips drive
+    layer: Vec<(Vec3, Quat, f32)>,
+    driven: Vec<bool>,
+}
+
+impl Scratch {
+    // Blend a layer's clips into `self.layer`: poses for override layers, and
+    // changes from each clip's first frame for additive ones.  Returns false if
+    // nothing on the layer is playing.
+    fn sample_layer(
+        &mut self,
+        layer: &mut AnimLayer,
+        assets: &Assets,
+        rig: RigRef,
+        rig_data: &Rig,
+        retarget: Option<&RetargetMap>,
+    ) -> bool {
+        let total: f32 = layer
+            .clips
+            .iter()
+            .filter(|c| assets.get_anim(c.anim).is_some())
+            .map(|c| c.weight)
+            .sum();
+        if total <= 0.0 {
+            return false;
+        }
+        let joint_count = rig_data.joint_count();
+        self.clip.resize(joint_count, Bone::default());
+        self.reference.resize(joint_count, Bone::default());
+        self.layer.clear();
+        self.layer.resize(
+            joint_count,
+            (Vec3::zero(), Quat::new(0.0, 0.0, 0.0, 0.0), 0.0),
+        );
+        self.driven.clear();
+        self.driven.resize(joint_count, false);
+        let additive = layer.blend == LayerBlend::Additive;
+        for clip in layer.clips.iter_mut() {
+            let anim = match assets.get_anim(clip.anim) {
+                Some(a) => a,
+                None => continue,
+            };
+            let time = clip.time;
+            let w = clip.weight / total;
+            let binding = clip.binding(anim, rig, rig_data, retarget);
+            for ji in binding.joints() {
+                self.driven[ji] = true;
+            }
+            rig_data.reset(&mut self.clip);
+            anim.sample_local(time, binding, &mut self.clip);
+            if additive {
+                rig_data.reset(&mut self.reference);
+                anim.sample_local(0.0, binding, &mut self.reference);
+            }
+            for (ji, (tr, rot, sc)) in self.layer.iter_mut().enumerate() {
+                let b = &self.clip[ji];
+                let (t, r, s) = if additive {
+                    let b0 = &self.reference[ji];
+                    (
+                        Vec3::from(b.translation) - Vec3::from(b0.translation),
+                        Quat::from(b0.rotation).invert() * Quat::from(b.rotation),
+                        b.scale / b0.scale,
+                    )
+                } else {
+                    (b.translation.into(), b.rotation.into(), b.scale)
+                };
+                // q and -q are the same rotation; keep them on one side before summing
+                let r = if rot.dot(r) < 0.0 { -r } else { r };
+                *tr += t * w;
+                *rot = *rot + r * w;
+                *sc += s * w;
+            }
+        }
+        for (_, rot, _) in self.layer.iter_mut() {
+            *rot = rot.normalize();
+        }
+        true
+    }
+}
+
+/// Movement taken out of the animation by root motion extraction, for the
+/// game to apply to the character itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RootMotion {
+    /// Ground-plane movement in the character's frame as it faced at the start
+    pub translation: Vec3,
+    /// Turn about the up (+y) axis in radians, counterclockwise seen from above
+    pub yaw: f32,
+}
+
+impl Default for RootMotion {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::zero(),
+            yaw: 0.0,
+        }
+    }
+}
+
+impl RootMotion {
+    /// This movement followed by `next`.
+    pub fn then(self, next: RootMotion) -> RootMotion {
+        RootMotion {
+            translation: self.translation + yaw_rotation(self.yaw).rotate_vector(next.translation),
+            yaw: self.yaw + next.yaw,
+        }
+    }
+}
+
+fn yaw_rotation(yaw: f32) -> Quat {
+    Quat::from_angle_y(cgmath::Rad(yaw))
+}
+
+// The joint root motion comes from, and the rig it's named for
+#[derive(Clone, Debug)]
+struct RootJoint {
+    rig: RigRef,
+    joint: String,
+}
+
+// Heading of a rig-space rotation about +y, measured from the joint's rest pose
+fn yaw_of(rot: Quat, rest: Quat) -> f32 {
+    let forward = (rot * rest.invert()).rotate_vector(Vec3::unit_z());
+    forward.x.atan2(forward.z)
+}
+
+fn wrap_angle(a: f32) -> f32 {
+    let a = (a + PI) % (2.0 * PI);
+    if a < 0.0 {
+        a + PI
+    } else {
+        a - PI
+    }
+}
+
+// Root motion between two times in a clip, without wrapping around
+fn root_segment(
+    anim: &Anim,
+    binding: &AnimBinding,
+    rig: &Rig,
+    ji: usize,
+    t0: f32,
+    t1: f32,
+) -> RootMotion {
+    let frame = rig.parent_rest_frame(ji);
+    let rest = (frame * rig.rest(ji).decomposed()).rot;
+    let at = |t: f32| {
+        let mut bone = rig.rest(ji);
+        anim.sample_joint(t, binding, ji, &mut bone);
+        let model = frame * bone.decomposed();
+        (model.disp, yaw_of(model.rot, rest))
+    };
+    let ((p0, yaw0), (p1, yaw1)) = (at(t0), at(t1));
+    let moved = Vec3::new(p1.x - p0.x, 0.0, p1.z - p0.z);
+    RootMotion {
+        translation: yaw_rotation(-yaw0).rotate_vector(moved),
+        yaw: wrap_angle(yaw1 - yaw0),
+    }
+}
+
+// Root motion for playback running from `from` to `to` seconds; looping clips
+// add up the motion of every pass through the clip
+fn root_motion(
+    anim: &Anim,
+    binding: &AnimBinding,
+    rig: &Rig,
+    ji: usize,
+    mode: PlayMode,
+    from: f32,
+    to: f32,
+) -> RootMotion {
+    let duration = anim.duration();
+    if mode != PlayMode::Loop || duration <= 0.0 {
+        let (t0, t1) = (mode.clip_time(from, duration), mode.clip_time(to, duration));
+        return root_segment(anim, binding, rig, ji, t0, t1);
+    }
+    let (pass0, pass1) = ((from / duration).floor(), (to / duration).floor());
+    let (t0, t1) = (from - pass0 * duration, to - pass1 * duration);
+    if pass0 == pass1 {
+        return root_segment(anim, binding, rig, ji, t0, t1);
+    }
+    let mut motion = root_segment(anim, binding, rig, ji, t0, duration);
+    let whole = root_segment(anim, binding, rig, ji, 0.0, duration);
+    for _ in 1..(pass1 - pass0) as usize {
+        motion = motion.then(whole);
+    }
+    motion.then(root_segment(anim, binding, rig, ji, 0.0, t1))
+}
+
+/// Plays animations on one character in layers.  Each layer blends any number
+/// of weighted clips, fading them in and out over time.  Layer 0 is the base
+/// pose; layers added above it override or add to what's beneath, optionally
+/// masked to some joints, e.g. a waving arm over a running body.
+///
+/// Call `update` once per simulation step and `pose` when rendering.  Methods
+/// taking an `AnimRef` act on the base layer; reach the others with `layer_mut`.
+///
+/// With `extract_root_motion`, the base layer's travel and turning come out of
+/// the pose and collect in `take_root_motion` for the game to move the character by.
+#[derive(Clone, Debug)]
+pub struct Animator {
+    layers: Vec<AnimLayer>,
+    // Named times in each clip, reported as playback passes them
+    markers: HashMap<AnimRef, Vec<(f32, String)>>,
+    events: Vec<AnimEvent>,
+    retarget: Option<RetargetMap>,
+    root: Option<RootJoint>,
+    // Extracted since the last take_root_motion
+    root_motion: RootMotion,
+    scratch: Scratch,
+}
+
+impl Default for Animator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Self {
+            layers: vec![AnimLayer::new(LayerBlend::Override, 1.0)],
+            markers: HashMap::new(),
+            events: vec![],
+            retarget: None,
+            root: None,
+            root_motion: RootMotion::default(),
+            scratch: Scratch::default(),
+        }
+    }
+    /// Play clips made for a differently named skeleton through `map`.
+    pub fn with_retarget(map: RetargetMap) -> Self {
+        Self {
+            retarget: Some(map),
+            ..Self::new()
+        }
+    }
+    /// Take `joint`'s ground-plane travel and yaw out of the pose of `rig`, and
+    /// collect it in `take_root_motion` instead.  Height and tilt stay in the pose.
+    pub fn extract_root_motion(&mut self, rig: RigRef, joint: impl Into<String>) {
+        self.root = Some(RootJoint {
+            rig,
+            joint: joint.into(),
+        });
+        self.root_motion = RootMotion::default();
+    }
+    /// Leave root motion in the pose again.
+    pub fn keep_root_motion(&mut self) {
+        self.root = None;
+        self.root_motion = RootMotion::default();
+    }
+    /// Root motion extracted by `update` since the last call.
+    pub fn take_root_motion(&mut self) -> RootMotion {
+        std::mem::take(&mut self.root_motion)
+    }
+    /// Add a layer above the existing ones and return its index.
+    pub fn add_layer(&mut self, blend: LayerBlend, weight: f32) -> usize {
+        self.layers.push(AnimLayer::new(blend, weight));
+        self.layers.len() - 1
+    }
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+    pub fn layer(&self, layer: usize) -> &AnimLayer {
+        &self.layers[layer]
+    }
+    pub fn layer_mut(&mut self, layer: usize) -> &mut AnimLayer {
+        &mut self.layers[layer]
+    }
+    /// Switch the base layer to `anim` right away from its start.
+    pub fn play(&mut self, anim: AnimRef) {
+        self.layers[0].play(anim);
+    }
+    /// Fade `anim` in on the base layer over `duration` seconds while the rest fades out.
+    pub fn crossfade(&mut self, anim: AnimRef, duration: f32) {
+        self.layers[0].crossfade(anim, duration);
+    }
+    /// Play `anim` on the base layer at a fixed weight; see `AnimLayer::set_weight`.
+    pub fn set_weight(&mut self, anim: AnimRef, weight: f32) {
+        self.layers[0].set_weight(anim, weight);
+    }
+    pub fn stop(&mut self, anim: AnimRef) {
+        self.layers[0].stop(anim);
+    }
+    pub fn weight(&self, anim: AnimRef) -> Option<f32> {
+        self.layers[0].weight(anim)
+    }
+    pub fn time(&self, anim: AnimRef) -> Option<f32> {
+        self.layers[0].time(anim)
+    }
+    pub fn set_speed(&mut self, anim: AnimRef, speed: f32) {
+        self.layers[0].set_speed(anim, speed);
+    }
+    pub fn set_mode(&mut self, anim: AnimRef, mode: PlayMode) {
+        self.layers[0].set_mode(anim, mode);
+    }
+    /// Report `name` whenever playback of `anim` passes `time` seconds into the clip.
+    pub fn add_event(&mut self, anim: AnimRef, time: f32, name: impl Into<String>) {
+        self.markers
+            .entry(anim)
+            .or_insert_with(Vec::new)
+            .push((time, name.into()));
+    }
+    /// What happened during the last `update`.
+    pub fn events(&self) -> &[AnimEvent] {
+        &self.events
+    }
+    /// Advance every clip on every layer by `dt` seconds and move fading weights along.
+    pub fn update(&mut self, assets: &Assets, dt: f32) {
+        self.events.clear();
+        let root = self.root.as_ref().and_then(|root| {
+            let rig = assets.get_rig(root.rig)?;
+            Some((root.rig, rig, rig.joint_index(&root.joint)?))
+        });
+        let mut moves = vec![];
+        for (li, layer) in self.layers.iter_mut().enumerate() {
+            for clip in layer.clips.iter_mut() {
+                let anim = assets.get_anim(clip.anim);
+                let duration = anim.map_or(0.0, |a| a.duration());
+                let from = clip.elapsed;
+                clip.advance(dt, duration, self.markers.get(&clip.anim), &mut self.events);
+                if let (0, Some(anim), Some((rig, rig_data, ji))) = (li, anim, root) {
+                    let (mode, to) = (clip.mode, clip.elapsed);
+                    let binding = clip.binding(anim, rig, rig_data, self.retarget.as_ref());
+                    let motion = root_motion(anim, binding, rig_data, ji, mode, from, to);
+                    moves.push((motion, clip.weight));
+                }
+            }
+            layer.clips.retain(|c| c.weight > 0.0 || c.target > 0.0);
+        }
+        // Blend the base layer's clips' motion by weight, like their poses
+        let total: f32 = moves.iter().map(|(_, w)| w).sum();
+        if total > 0.0 {
+            let mut step = RootMotion::default();
+            for (motion, w) in moves {
+                step.translation += motion.translation * (w / total);
+                step.yaw += motion.yaw * (w / total);
+            }
+            self.root_motion = self.root_motion.then(step);
+        }
+    }
+    /// Blend every layer, bottom to top, into a joint-local pose for `rig`.
+    /// Joints no clip drives keep the rig's rest pose.
+    pub fn pose_local(&mut self, assets: &Assets, rig: RigRef, bones: &mut [Bone]) {
+        let rig_data = match assets.get_rig(rig) {
+            Some(r) => r,
+            None => return,
+        };
+        rig_data.reset(bones);
+        let retarget = self.retarget.as_ref();
+        let scratch = &mut self.scratch;
+        for layer in self.layers.iter_mut() {
+            if layer.weight <= 0.0 || !scratch.sample_layer(layer, assets, rig, rig_data, retarget)
+            {
+                continue;
+            }
+            let stale = match (&layer.mask, &layer.mask_weights) {
+                (Some(_), Some((r, _))) => *r != rig,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if stale {
+                let weights = layer.mask.as_ref().unwrap().resolve(rig_data);
+                layer.mask_weights = Some((rig, weights));
+            }
+            let mask = layer.mask_weights.as_ref().map(|(_, w)| w);
+            for (ji, (b, (tr, rot, sc))) in bones.iter_mut().zip(scratch.layer.iter()).enumerate() {
+                let f = layer.weight * mask.map_or(1.0, |m| m[ji]);
+                if !scratch.driven[ji] || f <= 0.0 {
+                    continue;
+                }
+                let (bt, br) = (Vec3::from(b.translation), Quat::from(b.rotation));
+                match layer.blend {
+                    LayerBlend::Override => {
+                        b.translation = bt.lerp(*tr, f).into();
+                        b.rotation = nlerp_shortest(br, *rot, f).into();
+                        b.scale += (*sc - b.scale) * f;
+                    }
+                    LayerBlend::Additive => {
+                        let delta = nlerp_shortest(Quat::one(), *rot, f);
+                        b.translation = (bt + *tr * f).into();
+                        b.rotation = (br * delta).normalize().into();
+                        b.scale *= 1.0 + (*sc - 1.0) * f;
+                    }
+                }
+            }
+        }
+        let root = match &self.root {
+            Some(root) if root.rig == rig => rig_data.joint_index(&root.joint),
+            _ => None,
+        };
+        if let Some(ji) = root {
+            // Back to the rest position on the ground and the rest heading,
+            // keeping the rest of the motion
+            let frame = rig_data.parent_rest_frame(ji);
+            let rest = frame * rig_data.rest(ji).decomposed();
+            let mut model = frame * bones[ji].decomposed();
+            model.rot = yaw_rotation(-yaw_of(model.rot, rest.rot)) * model.rot;
+            model.disp.x = rest.disp.x;
+            model.disp.z = rest.disp.z;
+            if let Some(unframe) = frame.inverse_transform() {
+                let local = unframe * model;
+                bones[ji].translation = local.disp.into();
+                bones[ji].rotation = local.rot.normalize().into();
+            }
+        }
+    }
+    /// Blend the morph target weights that every layer's clips give the mesh on
+    /// node `node` into `weights`, which should start out as the model's
+    /// defaults.  Layer masks don't apply here, since they pick joints.
+    pub fn morph_weights(&self, assets: &Assets, node: &str, weights: &mut [f32]) {
+        let mut sampled = vec![0.0; weights.len()];
+        let mut reference = vec![0.0; weights.len()];
+        let mut blended = vec![0.0; weights.len()];
+        for layer in self.layers.iter().filter(|l| l.weight > 0.0) {
+            let additive = layer.blend == LayerBlend::Additive;
+            let mut total = 0.0;
+            blended.iter_mut().for_each(|w| *w = 0.0);
+            for clip in layer.clips.iter() {
+                let anim = match assets.get_anim(clip.anim) {
+                    Some(a) => a,
+                    None => continue,
+                };
+                // Targets the track leaves out stay as they were
+                sampled.copy_from_slice(weights);
+                reference.copy_from_slice(weights);
+                if !anim.sample_weights(clip.time, node, &mut sampled) {
+                    continue;
+                }
+                if additive {
+                    anim.sample_weights(0.0, node, &mut reference);
+                }
+                for ((b, s), r) in blended.iter_mut().zip(sampled.iter()).zip(reference.iter()) {
+                    let value = if additive { s - r } else { *s };
+                    *b += value * clip.weight;
+                }
+                total += clip.weight;
+            }
+            if total <= 0.0 {
+                continue;
+            }
+            for (w, b) in weights.iter_mut().zip(blended.iter()) {
+                let b = b / total;
+                if additive {
+                    *w += b * layer.weight;
+                } else {
+                    *w += (b - *w) * layer.weight;
+                }
+            }
+        }
+    }
+    /// Blend the playing clips and skin the result, ready for `render_anim`.
+    pub fn pose(&mut self, assets: &Assets, rig: RigRef, bones: &mut [Bone]) {
+        self.pose_local(assets, rig, bones);
+        if let Some(rig_data) = assets.get_rig(rig) {
+            rig_data.skin(bones);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn wrap_angle_stays_in_range() {
+        assert!(close(wrap_angle(0.0), 0.0));
+        assert!(close(wrap_angle(1.0), 1.0));
+        assert!(close(wrap_angle(-1.0), -1.0));
+        assert!(close(wrap_angle(1.5 * PI), -0.5 * PI));
+        assert!(close(wrap_angle(-1.5 * PI), 0.5 * PI));
+        assert!(close(wrap_angle(4.0 * PI + 0.25), 0.25));
+        assert!(close(wrap_angle(-4.0 * PI - 0.25), -0.25));
+        for i in -100..100 {
+            let a = wrap_angle(i as f32 * 0.37);
+            assert!(
+                (-PI..=PI).contains(&a),
+                "{} wrapped to {}",
+                i as f32 * 0.37,
+                a
+            );
+        }
+    }
+
+    #[test]
+    fn yaw_of_turn_about_y() {
+        let rest = Quat::one();
+        assert!(close(yaw_of(Quat::one(), rest), 0.0));
+        let quarter = Quat::from_angle_y(cgmath::Rad(0.5 * PI));
+        assert!(close(yaw_of(quarter, rest), 0.5 * PI));
+        // Measured from the rest pose, not from +z
+        assert!(close(yaw_of(quarter, quarter), 0.0));
+    }
+}
@@ -1,98 +1,1148 @@
 use crate::anim::*;
+use crate::camera::Camera;
+use crate::geom::*;
+use crate::lights::Light;
 use crate::model::*;
 use gltf;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-#[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct ModelRef(usize);
-#[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct RigRef(usize);
-#[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct AnimRef(usize);
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+// Handles are a slot index plus the generation of that slot when the asset was
+// stored; once the asset is unloaded the slot's generation moves on and old
+// handles stop resolving.
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct ModelRef(u32, u32);
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct RigRef(u32, u32);
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct AnimRef(u32, u32);
+
+/// Why loading an asset failed, and which file it was.
+#[derive(Debug)]
+pub struct AssetError {
+    pub path: PathBuf,
+    pub kind: AssetErrorKind,
+}
+
+#[derive(Debug)]
+pub enum AssetErrorKind {
+    /// The file extension isn't one we know how to load
+    UnsupportedFormat,
+    Obj(tobj::LoadError),
+    Gltf(gltf::Error),
+    Io(std::io::Error),
+    Audio(rodio::decoder::DecoderError),
+    /// The file is fine but uses a feature the engine doesn't handle
+    Unsupported(String),
+    /// The file is malformed or internally inconsistent
+    Invalid(String),
+}
+
+impl AssetError {
+    pub fn new(path: impl AsRef<Path>, kind: AssetErrorKind) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            kind,
+        }
+    }
+}
+
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Couldn't load {}: ", self.path.display())?;
+        match &self.kind {
+            AssetErrorKind::UnsupportedFormat => write!(f, "unsupported file format"),
+            AssetErrorKind::Obj(e) => write!(f, "{}", e),
+            AssetErrorKind::Gltf(e) => write!(f, "{}", e),
+            AssetErrorKind::Io(e) => write!(f, "{}", e),
+            AssetErrorKind::Audio(e) => write!(f, "{}", e),
+            AssetErrorKind::Unsupported(what) => write!(f, "{} not supported", what),
+            AssetErrorKind::Invalid(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl std::error::Error for AssetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            AssetErrorKind::Obj(e) => Some(e),
+            AssetErrorKind::Gltf(e) => Some(e),
+            AssetErrorKind::Io(e) => Some(e),
+            AssetErrorKind::Audio(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// Generational storage for one kind of asset.
+struct Store<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+impl<T> Store<T> {
+    fn new() -> Self {
+        Self {
+            slots: vec![],
+            free: vec![],
+        }
+    }
+    fn insert(&mut self, value: T) -> (u32, u32) {
+        match self.free.pop() {
+            Some(idx) => {
+                let slot = &mut self.slots[idx as usize];
+                slot.value = Some(value);
+                (idx, slot.generation)
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                });
+                (self.slots.len() as u32 - 1, 0)
+            }
+        }
+    }
+    fn get(&self, (idx, generation): (u32, u32)) -> Option<&T> {
+        self.slots
+            .get(idx as usize)
+            .filter(|s| s.generation == generation)
+            .and_then(|s| s.value.as_ref())
+    }
+    fn replace(&mut self, (idx, generation): (u32, u32), value: T) {
+        if let Some(slot) = self.slots.get_mut(idx as usize) {
+            if slot.generation == generation && slot.value.is_some() {
+                slot.value = Some(value);
+            }
+        }
+    }
+    fn remove(&mut self, (idx, generation): (u32, u32)) -> Option<T> {
+        let slot = self.slots.get_mut(idx as usize)?;
+        if slot.generation != generation {
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation += 1;
+        self.free.push(idx);
+        Some(value)
+    }
+}
+
+/// A material inside a loaded model.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialRef {
+    pub model: ModelRef,
+    pub index: usize,
+}
+
+/// A handle along with the name the file gave it (empty if it had none).
+#[derive(Clone, Debug)]
+pub struct Named<T> {
+    pub name: String,
+    pub handle: T,
+}
+
+fn find<T: Copy>(items: &[Named<T>], name: &str) -> Option<T> {
+    items.iter().find(|n| n.name == name).map(|n| n.handle)
+}
+
+/// One node of a file's scene graph.
+#[derive(Clone, Debug)]
+pub struct SceneNode {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// Transform relative to the parent node
+    pub local: Mat4,
+    /// Transform relative to the scene root, i.e. every ancestor's `local` applied in turn
+    pub world: Mat4,
+    /// Index into `LoadedScene::meshes`
+    pub mesh: Option<usize>,
+    /// Index into `LoadedScene::rigs` if the mesh is skinned
+    pub skin: Option<usize>,
+    /// Index into `LoadedScene::lights`
+    pub light: Option<usize>,
+    /// Index into `LoadedScene::cameras`
+    pub camera: Option<usize>,
+}
+
+// Fill in world transforms from the roots down.  Every node has to be reached
+// exactly once, so a cycle or a node with two parents is an error.
+fn compute_world(nodes: &mut [SceneNode]) -> Result<(), AssetErrorKind> {
+    let mut visited = vec![false; nodes.len()];
+    let mut stack: Vec<(usize, Mat4)> = (0..nodes.len())
+        .filter(|n| nodes[*n].parent.is_none())
+        .map(|n| (n, Mat4::identity()))
+        .collect();
+    while let Some((n, parent_world)) = stack.pop() {
+        if visited[n] {
+            return Err(AssetErrorKind::Invalid(format!(
+                "node {} is reached twice; the node graph has a cycle",
+                n
+            )));
+        }
+        visited[n] = true;
+        let world = parent_world * nodes[n].local;
+        nodes[n].world = world;
+        stack.extend(nodes[n].children.iter().map(|c| (*c, world)));
+    }
+    match visited.iter().position(|v| !v) {
+        Some(n) => Err(AssetErrorKind::Invalid(format!(
+            "node {} is in a cycle with no root",
+            n
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Everything one file produced.  An `.obj` gives one mesh and its materials;
+/// a glTF file gives a mesh per glTF mesh, plus its skins and animations.
+#[derive(Clone, Debug)]
+pub struct LoadedScene {
+    pub path: PathBuf,
+    pub meshes: Vec<Named<ModelRef>>,
+    pub rigs: Vec<Named<RigRef>>,
+    pub anims: Vec<Named<AnimRef>>,
+    pub materials: Vec<Named<MaterialRef>>,
+    /// The scene graph, indexed like the file's nodes; an `.obj` is a single node
+    pub nodes: Vec<SceneNode>,
+    /// The nodes at the top of the file's default scene
+    pub roots: Vec<usize>,
+    /// KHR_lights_punctual lights, placed at their nodes' world transforms
+    pub lights: Vec<Named<Light>>,
+    /// Viewpoints from the file's camera nodes
+    pub cameras: Vec<Named<Camera>>,
+}
+
+impl LoadedScene {
+    pub fn mesh(&self, name: &str) -> Option<ModelRef> {
+        find(&self.meshes, name)
+    }
+    pub fn rig(&self, name: &str) -> Option<RigRef> {
+        find(&self.rigs, name)
+    }
+    pub fn anim(&self, name: &str) -> Option<AnimRef> {
+        find(&self.anims, name)
+    }
+    pub fn material(&self, name: &str) -> Option<MaterialRef> {
+        find(&self.materials, name)
+    }
+    pub fn light(&self, name: &str) -> Option<Light> {
+        find(&self.lights, name)
+    }
+    pub fn camera(&self, name: &str) -> Option<Camera> {
+        find(&self.cameras, name)
+    }
+    pub fn node(&self, name: &str) -> Option<&SceneNode> {
+        self.nodes.iter().find(|n| n.name == name)
+    }
+    /// The first mesh in the file, which for an `.obj` is the whole model.
+    pub fn model(&self) -> Option<ModelRef> {
+        self.meshes.first().map(|m| m.handle)
+    }
+}
+
+// What a loaded file produced and how many loads are still holding on to it
+struct Source {
+    scene: LoadedScene,
+    refs: usize,
+    // Other files it was read from, so changes to them reload it too
+    files: Vec<PathBuf>,
+}
+
+/// How far along a background load is, from 0.0 to 1.0.
+#[derive(Clone, Default)]
+struct Progress(Arc<AtomicU32>);
+
+impl Progress {
+    fn set(&self, p: f32) {
+        self.0.store(p.to_bits(), Ordering::Relaxed);
+    }
+    fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// A load running in the background.  Poll it with `Assets::poll_model` or
+/// `Assets::poll_scene` until it's done.  Dropping it before then gives the
+/// load up, and whatever it loaded is released.
+pub struct Pending<T> {
+    id: usize,
+    progress: Progress,
+    abandon: Sender<usize>,
+    _loads: std::marker::PhantomData<T>,
+}
+
+impl<T> Pending<T> {
+    pub fn progress(&self) -> f32 {
+        self.progress.get()
+    }
+}
+
+impl<T> Drop for Pending<T> {
+    fn drop(&mut self) {
+        // Loads that were already polled are gone from `Assets`, so this is a no-op for them
+        let _ = self.abandon.send(self.id);
+    }
+}
+
+pub type PendingModel = Pending<ModelRef>;
+pub type PendingScene = Pending<LoadedScene>;
+
+/// The CPU side of a loaded file: everything decoded, nothing on the GPU yet.
+struct SceneData {
+    meshes: Vec<(String, ModelData)>,
+    rigs: Vec<(String, Rig)>,
+    anims: Vec<(String, Anim)>,
+    // Every model from one file shares the same material list
+    materials: Vec<String>,
+    nodes: Vec<SceneNode>,
+    roots: Vec<usize>,
+    lights: Vec<Named<Light>>,
+    cameras: Vec<Named<Camera>>,
+    // Materials, textures and buffers the file points at
+    files: Vec<PathBuf>,
+}
+
+fn decode(path: &Path, progress: &Progress) -> Result<SceneData, AssetError> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("obj") => {
+            let model = ModelData::load_obj(path)?;
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Ok(SceneData {
+                materials: model.materials.iter().map(|m| m.name.clone()).collect(),
+                nodes: vec![SceneNode {
+                    name: name.clone(),
+                    parent: None,
+                    children: vec![],
+                    local: Mat4::identity(),
+                    world: Mat4::identity(),
+                    mesh: Some(0),
+                    skin: None,
+                    light: None,
+                    camera: None,
+                }],
+                roots: vec![0],
+                lights: vec![],
+                cameras: vec![],
+                meshes: vec![(name, model)],
+                rigs: vec![],
+                anims: vec![],
+                files: obj_files(path),
+            })
+        }
+        // gltf::import reads .glb containers and data: URIs as well as plain files
+        Some("gltf") | Some("glb") => decode_gltf(path, progress),
+        _ => Err(AssetError::new(path, AssetErrorKind::UnsupportedFormat)),
+    }
+}
+
+fn decode_gltf(path: &Path, progress: &Progress) -> Result<SceneData, AssetError> {
+    let err = |kind| AssetError::new(path, kind);
+    let (g, bufs, images) = gltf::import(path).map_err(|e| err(AssetErrorKind::Gltf(e)))?;
+    progress.set(0.5);
+    let files = gltf_files(&g, path);
+    let name = |n: Option<&str>| n.unwrap_or("").to_string();
+    let meshes = g
+        .meshes()
+        .map(|mesh| {
+            let mname = name(mesh.name());
+            ModelData::from_gltf(&g, &bufs, &images, mesh).map(|m| (mname, m))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(err)?;
+    progress.set(0.7);
+    let rigs = g
+        .skins()
+        .map(|skin| {
+            let sname = name(skin.name());
+            Rig::from_gltf(&g, &bufs, skin).map(|r| (sname, r))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(err)?;
+    // Animations refer to joints by name, so they aren't tied to this file's rigs
+    let anims = g
+        .animations()
+        .map(|ganim| {
+            let aname = name(ganim.name());
+            Anim::from_gltf(&g, &bufs, ganim).map(|a| (aname, a))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(err)?;
+    let mut nodes: Vec<SceneNode> = g
+        .nodes()
+        .map(|node| SceneNode {
+            name: name(node.name()),
+            parent: None,
+            children: node.children().map(|c| c.index()).collect(),
+            local: node.transform().matrix().into(),
+            world: Mat4::identity(),
+            mesh: node.mesh().map(|m| m.index()),
+            skin: node.skin().map(|s| s.index()),
+            light: None,
+            camera: None,
+        })
+        .collect();
+    for n in 0..nodes.len() {
+        for c in nodes[n].children.clone() {
+            nodes[c].parent = Some(n);
+        }
+    }
+    compute_world(&mut nodes).map_err(err)?;
+    let mut lights = vec![];
+    let mut cameras = vec![];
+    for node in g.nodes() {
+        let n = node.index();
+        let world = nodes[n].world;
+        if let Some(light) = node.light() {
+            let light_name = light.name().unwrap_or(&nodes[n].name).to_string();
+            nodes[n].light = Some(lights.len());
+            lights.push(Named {
+                name: light_name,
+                handle: convert_light(&light, world),
+            });
+        }
+        if let Some(camera) = node.camera() {
+            let camera_name = camera.name().unwrap_or(&nodes[n].name).to_string();
+            match convert_camera(&camera, world) {
+                Some(c) => {
+                    nodes[n].camera = Some(cameras.len());
+                    cameras.push(Named {
+                        name: camera_name,
+                        handle: c,
+                    });
+                }
+                None => eprintln!(
+                    "Skipping orthographic camera {:?} in {:?}",
+                    camera_name, path
+                ),
+            }
+        }
+    }
+    let roots = match g.default_scene().or_else(|| g.scenes().next()) {
+        Some(scene) => scene.nodes().map(|n| n.index()).collect(),
+        None => (0..nodes.len())
+            .filter(|n| nodes[*n].parent.is_none())
+            .collect(),
+    };
+    progress.set(0.9);
+    Ok(SceneData {
+        nodes,
+        roots,
+        lights,
+        cameras,
+        materials: meshes
+            .first()
+            .map(|(_, m)| m.materials.iter().map(|mat| mat.name.clone()).collect())
+            .unwrap_or_default(),
+        meshes,
+        rigs,
+        anims,
+        files,
+    })
+}
+
+// The .mtl files an .obj names and the textures they name, where `load_obj` looks for them
+fn obj_files(path: &Path) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let obj = std::fs::read_to_string(path).unwrap_or_default();
+    let mut files = vec![];
+    for line in obj.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("mtllib") {
+            continue;
+        }
+        for mtl in words {
+            let mtl = dir.join(mtl);
+            if let Ok((materials, _)) = tobj::load_mtl(&mtl) {
+                files.extend(
+                    materials
+                        .into_iter()
+                        .filter(|m| !m.diffuse_texture.is_empty())
+                        .map(|m| dir.join(m.diffuse_texture)),
+                );
+            }
+            files.push(mtl);
+        }
+    }
+    files
+}
+
+// The buffers and images a glTF file keeps in other files, resolved the way
+// gltf::import does; embedded data and other URI schemes have no file to watch
+fn gltf_files(g: &gltf::Document, path: &Path) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let buffers = g.buffers().filter_map(|b| match b.source() {
+        gltf::buffer::Source::Uri(uri) => Some(uri),
+        gltf::buffer::Source::Bin => None,
+    });
+    let images = g.images().filter_map(|i| match i.source() {
+        gltf::image::Source::Uri { uri, .. } => Some(uri),
+        gltf::image::Source::View { .. } => None,
+    });
+    buffers
+        .chain(images)
+        .filter(|uri| !uri.contains(':'))
+        .map(|uri| dir.join(uri))
+        .collect()
+}
+
+// notify reports absolute paths, so compare against canonical ones
+fn canonical(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .map(|f| std::fs::canonicalize(&f).unwrap_or(f))
+        .collect()
+}
+
+// glTF lights shine down their node's -Z.  Intensity stays in the file's units,
+// candela for point and spot lights and lux for directional ones.
+fn convert_light(light: &gltf::khr_lights_punctual::Light, world: Mat4) -> Light {
+    use gltf::khr_lights_punctual::Kind;
+    let color = Vec3::from(light.color());
+    let pos = Pos3::from_homogeneous(world * Vec4::unit_w());
+    let dir = (world * -Vec4::unit_z()).truncate();
+    let shape = match light.kind() {
+        Kind::Directional => Light::directional(dir, color),
+        Kind::Point => Light::point(pos, color),
+        Kind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } => Light::spot(
+            pos,
+            dir,
+            color,
+            cgmath::Rad(inner_cone_angle),
+            cgmath::Rad(outer_cone_angle),
+        ),
+    };
+    shape.with_intensity(light.intensity())
+}
+
+// glTF cameras look down their node's -Z with +Y up.  Orthographic cameras
+// aren't supported by `Camera`.
+fn convert_camera(camera: &gltf::Camera, world: Mat4) -> Option<Camera> {
+    let p = match camera.projection() {
+        gltf::camera::Projection::Perspective(p) => p,
+        gltf::camera::Projection::Orthographic(_) => return None,
+    };
+    let eye = Pos3::from_homogeneous(world * Vec4::unit_w());
+    let forward = (world * -Vec4::unit_z()).truncate();
+    Some(Camera {
+        eye,
+        target: eye + forward,
+        up: (world * Vec4::unit_y()).truncate(),
+        // Replaced by the window's aspect ratio in `Engine::set_camera`
+        aspect: p.aspect_ratio().unwrap_or(1.0),
+        fovy: cgmath::Deg::from(cgmath::Rad(p.yfov())).0,
+        znear: p.znear(),
+        zfar: p.zfar().unwrap_or(200.0),
+    })
+}
+
+// How many files can be decoding at once
+const LOAD_WORKERS: usize = 2;
+
+// A file for a worker thread to decode
+struct LoadJob {
+    id: usize,
+    path: PathBuf,
+    progress: Progress,
+}
+
+// Decode jobs off the shared queue until the `Assets` that owns it goes away
+fn spawn_workers(jobs: Receiver<LoadJob>, decoded: Sender<(usize, Result<SceneData, AssetError>)>) {
+    let jobs = Arc::new(Mutex::new(jobs));
+    for n in 0..LOAD_WORKERS {
+        let jobs = jobs.clone();
+        let decoded = decoded.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("asset loader {}", n))
+            .spawn(move || loop {
+                // Hold the lock while waiting for a job but not while decoding it
+                let job = match jobs.lock().map(|rx| rx.recv()) {
+                    Ok(Ok(job)) => job,
+                    _ => return,
+                };
+                let result = decode(&job.path, &job.progress);
+                job.progress.set(0.9);
+                // If nobody is listening any more the engine has shut down
+                if decoded.send((job.id, result)).is_err() {
+                    return;
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("Couldn't start asset loader thread: {}", e);
+        }
+    }
+}
+
+struct InFlight {
+    path: PathBuf,
+    progress: Progress,
+    // The `Pending` was dropped, so throw the result away when it arrives
+    abandoned: bool,
+}
 
 pub struct Assets {
     asset_root: PathBuf,
-    models: HashMap<ModelRef, Model>,
-    rigs: HashMap<RigRef, Rig>,
-    anims: HashMap<AnimRef, Anim>,
+    models: Store<Model>,
+    rigs: Store<Rig>,
+    anims: Store<Anim>,
+    // Loaded files by canonical path, so loading twice shares one copy and
+    // so we can rebuild things in place when the file changes
+    sources: HashMap<PathBuf, Source>,
+    // Which file each model came from, for releasing by model
+    model_sources: HashMap<ModelRef, PathBuf>,
+    // Keep the watcher alive for as long as we want events
+    _watcher: Option<RecommendedWatcher>,
+    changes: Receiver<DebouncedEvent>,
+    fallback_model: Option<ModelRef>,
+    // Background loads: decoded on worker threads, uploaded in `finish_loads`
+    next_load: usize,
+    in_flight: HashMap<usize, InFlight>,
+    jobs: Sender<LoadJob>,
+    decoded_rx: Receiver<(usize, Result<SceneData, AssetError>)>,
+    finished: HashMap<usize, Result<LoadedScene, AssetError>>,
+    abandon_tx: Sender<usize>,
+    abandon_rx: Receiver<usize>,
 }
 impl Assets {
     pub fn new(asset_root: impl AsRef<Path>) -> Self {
-        // ... register filesystem watchers with crate notify = "4.0.15":
+        let asset_root = asset_root.as_ref().to_owned();
+        let (tx, changes) = channel();
+        let watcher = notify::watcher(tx, Duration::from_millis(250))
+            .and_then(|mut w| w.watch(&asset_root, RecursiveMode::Recursive).map(|_| w))
+            .map_err(|e| eprintln!("Not watching {:?} for changes: {}", asset_root, e))
+            .ok();
+        let (jobs, job_rx) = channel();
+        let (decoded_tx, decoded_rx) = channel();
+        spawn_workers(job_rx, decoded_tx);
+        let (abandon_tx, abandon_rx) = channel();
         Self {
-            asset_root: asset_root.as_ref().to_owned(),
-            models: HashMap::new(),
-            rigs: HashMap::new(),
-            anims: HashMap::new(),
+            asset_root,
+            models: Store::new(),
+            rigs: Store::new(),
+            anims: Store::new(),
+            sources: HashMap::new(),
+            model_sources: HashMap::new(),
+            _watcher: watcher,
+            changes,
+            fallback_model: None,
+            next_load: 0,
+            in_flight: HashMap::new(),
+            jobs,
+            decoded_rx,
+            finished: HashMap::new(),
+            abandon_tx,
+            abandon_rx,
         }
     }
+    /// Where a file named relative to the asset root actually is, for data
+    /// files the engine doesn't load itself.
+    pub fn asset_path(&self, file: impl AsRef<Path>) -> PathBuf {
+        self.asset_root.join(file)
+    }
+    fn source_path(&self, file: impl AsRef<Path>) -> PathBuf {
+        // notify reports absolute paths, so remember canonical ones
+        let path = self.asset_root.join(file);
+        std::fs::canonicalize(&path).unwrap_or(path)
+    }
+    // If this file is already loaded, count another user and hand back what it made
+    fn retain(&mut self, path: &Path) -> Option<LoadedScene> {
+        let source = self.sources.get_mut(path)?;
+        source.refs += 1;
+        Some(source.scene.clone())
+    }
+    fn store(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: PathBuf,
+        data: SceneData,
+    ) -> LoadedScene {
+        let meshes: Vec<_> = data
+            .meshes
+            .into_iter()
+            .map(|(name, m)| {
+                let (idx, gen) = self.models.insert(m.upload(device, queue, layout));
+                Named {
+                    name,
+                    handle: ModelRef(idx, gen),
+                }
+            })
+            .collect();
+        let rigs = data
+            .rigs
+            .into_iter()
+            .map(|(name, r)| {
+                let (idx, gen) = self.rigs.insert(r);
+                Named {
+                    name,
+                    handle: RigRef(idx, gen),
+                }
+            })
+            .collect();
+        let anims = data
+            .anims
+            .into_iter()
+            .map(|(name, a)| {
+                let (idx, gen) = self.anims.insert(a);
+                Named {
+                    name,
+                    handle: AnimRef(idx, gen),
+                }
+            })
+            .collect();
+        let materials = match meshes.first() {
+            Some(first) => data
+                .materials
+                .into_iter()
+                .enumerate()
+                .map(|(index, name)| Named {
+                    name,
+                    handle: MaterialRef {
+                        model: first.handle,
+                        index,
+                    },
+                })
+                .collect(),
+            None => vec![],
+        };
+        let scene = LoadedScene {
+            path: path.clone(),
+            meshes,
+            rigs,
+            anims,
+            materials,
+            nodes: data.nodes,
+            roots: data.roots,
+            lights: data.lights,
+            cameras: data.cameras,
+        };
+        for m in scene.meshes.iter() {
+            self.model_sources.insert(m.handle, path.clone());
+        }
+        self.sources.insert(
+            path,
+            Source {
+                scene: scene.clone(),
+                refs: 1,
+                files: canonical(data.files),
+            },
+        );
+        scene
+    }
+    /// Load an `.obj`, `.gltf` or `.glb` file, or return the existing scene if
+    /// it's already loaded.  Each call should be paired with a `release_scene`
+    /// when the caller is done.
+    pub fn load_scene(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        file: impl AsRef<Path>,
+    ) -> Result<LoadedScene, AssetError> {
+        let path = self.source_path(file);
+        if let Some(scene) = self.retain(&path) {
+            return Ok(scene);
+        }
+        let data = decode(&path, &Progress::default())?;
+        Ok(self.store(device, queue, layout, path, data))
+    }
+    /// Load a file with `load_scene` and return its first mesh.
+    /// Each call should be paired with a `release_model` when the caller is done.
     pub fn load_model(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
         model: impl AsRef<Path>,
+    ) -> Result<ModelRef, AssetError> {
+        let scene = self.load_scene(device, queue, layout, model)?;
+        first_model(scene)
+    }
+    /// A checkered cube to draw in place of models that failed to load.
+    pub fn fallback_model(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
     ) -> ModelRef {
-        let mref = ModelRef(self.models.len());
-        let ar = &self.asset_root;
-        self.models.insert(
-            mref,
-            Model::load(device, queue, layout, ar.join(&model)).unwrap(),
-        );
+        if let Some(mref) = self.fallback_model {
+            return mref;
+        }
+        let (idx, gen) = self.models.insert(Model::fallback(device, queue, layout));
+        let mref = ModelRef(idx, gen);
+        self.fallback_model = Some(mref);
         mref
     }
     pub fn get_model(&self, model: ModelRef) -> Option<&Model> {
-        self.models.get(&model)
+        self.models.get((model.0, model.1))
+    }
+    pub fn get_material(&self, material: MaterialRef) -> Option<&Material> {
+        self.get_model(material.model)?
+            .materials
+            .get(material.index)
+    }
+    pub fn get_rig(&self, rig: RigRef) -> Option<&Rig> {
+        self.rigs.get((rig.0, rig.1))
+    }
+    pub fn get_anim(&self, anim: AnimRef) -> Option<&Anim> {
+        self.anims.get((anim.0, anim.1))
+    }
+    fn load_async(&mut self, file: impl AsRef<Path>) -> (usize, Progress) {
+        let path = self.source_path(file);
+        let id = self.next_load;
+        self.next_load += 1;
+        let progress = Progress::default();
+        if let Some(scene) = self.retain(&path) {
+            progress.set(1.0);
+            self.finished.insert(id, Ok(scene));
+        } else {
+            let job = LoadJob {
+                id,
+                path: path.clone(),
+                progress: progress.clone(),
+            };
+            // The workers hold the receiver until `jobs` is dropped, so this can't fail
+            let _ = self.jobs.send(job);
+        }
+        self.in_flight.insert(
+            id,
+            InFlight {
+                path,
+                progress: progress.clone(),
+                abandoned: false,
+            },
+        );
+        (id, progress)
     }
-    pub fn load_gltf(
+    /// Start loading a file on a worker thread.  The returned handle reports
+    /// progress; `poll_scene` hands over the scene once it's on the GPU.
+    pub fn load_scene_async(&mut self, file: impl AsRef<Path>) -> PendingScene {
+        let (id, progress) = self.load_async(file);
+        Pending {
+            id,
+            progress,
+            abandon: self.abandon_tx.clone(),
+            _loads: std::marker::PhantomData,
+        }
+    }
+    /// Like `load_scene_async`, but `poll_model` hands over just the first mesh.
+    pub fn load_model_async(&mut self, model: impl AsRef<Path>) -> PendingModel {
+        let (id, progress) = self.load_async(model);
+        Pending {
+            id,
+            progress,
+            abandon: self.abandon_tx.clone(),
+            _loads: std::marker::PhantomData,
+        }
+    }
+    fn poll(&mut self, id: usize) -> Option<Result<LoadedScene, AssetError>> {
+        let done = self.finished.remove(&id)?;
+        self.in_flight.remove(&id);
+        Some(done)
+    }
+    /// `None` while the load is still going; the result once, when it's done.
+    pub fn poll_scene(
+        &mut self,
+        pending: &PendingScene,
+    ) -> Option<Result<LoadedScene, AssetError>> {
+        self.poll(pending.id)
+    }
+    pub fn poll_model(&mut self, pending: &PendingModel) -> Option<Result<ModelRef, AssetError>> {
+        self.poll(pending.id).map(|done| done.and_then(first_model))
+    }
+    /// Upload whatever the worker threads have finished decoding.  Called by the
+    /// engine on the render thread once per frame.
+    pub fn finish_loads(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) {
+        while let Ok(id) = self.abandon_rx.try_recv() {
+            match self.finished.remove(&id) {
+                Some(done) => {
+                    self.in_flight.remove(&id);
+                    if let Ok(scene) = done {
+                        self.release(&scene.path);
+                    }
+                }
+                None => {
+                    if let Some(f) = self.in_flight.get_mut(&id) {
+                        f.abandoned = true;
+                    }
+                }
+            }
+        }
+        while let Ok((id, decoded)) = self.decoded_rx.try_recv() {
+            let (path, progress) = match self.in_flight.get(&id) {
+                Some(f) if f.abandoned => {
+                    self.in_flight.remove(&id);
+                    continue;
+                }
+                Some(f) => (f.path.clone(), f.progress.clone()),
+                None => continue,
+            };
+            let result = decoded.map(|data| {
+                // Someone may have loaded the same file while this was decoding
+                match self.retain(&path) {
+                    Some(scene) => scene,
+                    None => self.store(device, queue, layout, path, data),
+                }
+            });
+            progress.set(1.0);
+            self.finished.insert(id, result);
+        }
+    }
+    /// Drop one reference to the file this model came from; when none are left
+    /// the file's assets are unloaded.
+    pub fn release_model(&mut self, model: ModelRef) {
+        if let Some(path) = self.model_sources.get(&model).cloned() {
+            self.release(&path);
+        }
+    }
+    /// Drop one reference to a loaded file; when none are left its models,
+    /// rigs and animations are unloaded.
+    pub fn release_scene(&mut self, scene: &LoadedScene) {
+        self.release(&scene.path);
+    }
+    /// Unload the file this model came from right away, however many references remain.
+    /// Every handle to its assets goes stale.
+    pub fn unload_model(&mut self, model: ModelRef) {
+        if let Some(path) = self.model_sources.get(&model).cloned() {
+            self.unload(&path);
+        }
+    }
+    /// Unload a file right away, however many references remain.
+    pub fn unload_scene(&mut self, scene: &LoadedScene) {
+        self.unload(&scene.path);
+    }
+    fn release(&mut self, path: &Path) {
+        if let Some(source) = self.sources.get_mut(path) {
+            source.refs = source.refs.saturating_sub(1);
+            if source.refs == 0 {
+                self.unload(path);
+            }
+        }
+    }
+    fn unload(&mut self, path: &Path) {
+        // Dropping the models releases their GPU buffers and textures
+        if let Some(source) = self.sources.remove(path) {
+            for Named { handle, .. } in source.scene.meshes {
+                self.model_sources.remove(&handle);
+                self.models.remove((handle.0, handle.1));
+            }
+            for Named { handle, .. } in source.scene.rigs {
+                self.rigs.remove((handle.0, handle.1));
+            }
+            for Named { handle, .. } in source.scene.anims {
+                self.anims.remove((handle.0, handle.1));
+            }
+        }
+    }
+    /// Rebuild any models, rigs and animations whose files changed on disk.
+    /// Handles stay the same; they just point at the new data.
+    pub fn reload_changed(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
-        gltf_file: impl AsRef<Path>,
-    ) -> (Vec<ModelRef>, Vec<RigRef>, Vec<AnimRef>) {
-        dbg!(gltf_file.as_ref());
-        let gltf_file = gltf_file.as_ref();
-        let gltf_file_path = self.asset_root.join(gltf_file);
-        let (g, bufs, images) = gltf::import(gltf_file_path).unwrap();
-        let mut models = vec![];
-        let mut rigs = vec![];
-        let mut anims = vec![];
-        for mesh in g.meshes() {
-            let model = Model::from_gltf(device, queue, layout, &g, &bufs, &images, mesh);
-            let mref = ModelRef(self.models.len());
-            models.push(mref);
-            self.models.insert(mref, model);
-        }
-        let mut active_rig = None;
-        for skin in g.skins() {
-            // build the rig out of the joints
-            let rig = Rig::from_gltf(&g, &bufs, skin);
-            let rref = RigRef(self.rigs.len());
-            self.rigs.insert(rref, rig);
-            rigs.push(rref);
-            active_rig = Some(rref);
-        }
-        for ganim in g.animations() {
-            // TODO make animations retargetable, use target(rig) to assign targets to joints;
-            // For now, just use the last rig
-            // build an animation out of this anim's channels and samplers
-            let anim = Anim::from_gltf(
-                &g,
-                &bufs,
-                ganim,
-                self.get_rig(active_rig.unwrap()).as_ref().unwrap(),
+    ) {
+        let mut changed = vec![];
+        while let Ok(ev) = self.changes.try_recv() {
+            match ev {
+                DebouncedEvent::Create(p)
+                | DebouncedEvent::Write(p)
+                | DebouncedEvent::Rename(_, p) => changed.push(p),
+                DebouncedEvent::Error(e, p) => eprintln!("Watch error {:?}: {}", p, e),
+                _ => {}
+            }
+        }
+        changed.sort();
+        changed.dedup();
+        // A changed texture, .mtl or .bin file reloads every file that reads it
+        let mut to_reload: Vec<PathBuf> = vec![];
+        for p in canonical(changed) {
+            if self.sources.contains_key(&p) {
+                to_reload.push(p.clone());
+            }
+            to_reload.extend(
+                self.sources
+                    .iter()
+                    .filter(|(_, source)| source.files.contains(&p))
+                    .map(|(src, _)| src.clone()),
             );
-            let aref = AnimRef(self.anims.len());
-            anims.push(aref);
-            self.anims.insert(aref, anim);
         }
-        (models, rigs, anims)
+        to_reload.sort();
+        to_reload.dedup();
+        for src in to_reload {
+            self.reload(device, queue, layout, &src);
+        }
     }
-    pub fn get_rig(&self, rig: RigRef) -> Option<&Rig> {
-        self.rigs.get(&rig)
+    fn reload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        src: &Path,
+    ) {
+        let scene = match self.sources.get(src) {
+            Some(source) => &source.scene,
+            None => return,
+        };
+        let data = match decode(src, &Progress::default()) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("{}, keeping old version", e);
+                return;
+            }
+        };
+        if data.meshes.len() != scene.meshes.len()
+            || data.rigs.len() != scene.rigs.len()
+            || data.anims.len() != scene.anims.len()
+        {
+            eprintln!(
+                "Couldn't reload {:?}: meshes, skins or animations were added or removed",
+                src
+            );
+            return;
+        }
+        let (nodes, roots, lights, cameras) = (data.nodes, data.roots, data.lights, data.cameras);
+        let files = canonical(data.files);
+        for ((_, model), mref) in data.meshes.into_iter().zip(scene.meshes.iter()) {
+            let ModelRef(idx, gen) = mref.handle;
+            self.models
+                .replace((idx, gen), model.upload(device, queue, layout));
+        }
+        for ((_, rig), rref) in data.rigs.into_iter().zip(scene.rigs.iter()) {
+            let RigRef(idx, gen) = rref.handle;
+            self.rigs.replace((idx, gen), rig);
+        }
+        for ((_, anim), aref) in data.anims.into_iter().zip(scene.anims.iter()) {
+            let AnimRef(idx, gen) = aref.handle;
+            self.anims.replace((idx, gen), anim);
+        }
+        if let Some(source) = self.sources.get_mut(src) {
+            source.scene.nodes = nodes;
+            source.scene.roots = roots;
+            source.scene.lights = lights;
+            source.scene.cameras = cameras;
+            source.files = files;
+        }
+        println!("Reloaded {:?}", src);
     }
-    pub fn get_anim(&self, anim: AnimRef) -> Option<&Anim> {
-        self.anims.get(&anim)
+}
+
+fn first_model(scene: LoadedScene) -> Result<ModelRef, AssetError> {
+    scene.model().ok_or_else(|| {
+        AssetError::new(
+            scene.path,
+            AssetErrorKind::Invalid("no meshes in file".into()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gltf_files_follow_relative_uris() {
+        let json = r#"{
+            "asset": { "version": "2.0" },
+            "buffers": [
+                { "uri": "data/mesh.bin", "byteLength": 4 },
+                { "uri": "data:application/octet-stream;base64,AAAAAA==", "byteLength": 4 }
+            ],
+            "images": [{ "uri": "textures/skin.png" }]
+        }"#;
+        let g = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+        let files = gltf_files(&g, Path::new("content/fox/fox.gltf"));
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("content/fox/data/mesh.bin"),
+                PathBuf::from("content/fox/textures/skin.png"),
+            ]
+        );
+    }
+
+    #[test]
+    fn obj_files_follow_materials() {
+        let dir = std::env::temp_dir().join(format!("obj-files-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("mats")).unwrap();
+        std::fs::write(dir.join("cube.obj"), "mtllib mats/cube.mtl\nv 0 0 0\n").unwrap();
+        std::fs::write(
+            dir.join("mats/cube.mtl"),
+            "newmtl skin\nmap_Kd textures/skin.png\nnewmtl plain\n",
+        )
+        .unwrap();
+        let files = obj_files(&dir.join("cube.obj"));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(
+            files,
+            vec![dir.join("textures/skin.png"), dir.join("mats/cube.mtl")]
+        );
+    }
+
+    #[test]
+    fn store_gets_what_was_inserted() {
+        let mut store = Store::new();
+        let a = store.insert("a");
+        let b = store.insert("b");
+        assert_ne!(a, b);
+        assert_eq!(store.get(a), Some(&"a"));
+        assert_eq!(store.get(b), Some(&"b"));
+        assert_eq!(store.get((7, 0)), None);
+    }
+
+    #[test]
+    fn store_reuses_slots_with_new_generations() {
+        let mut store = Store::new();
+        let a = store.insert("a");
+        assert_eq!(store.remove(a), Some("a"));
+        assert_eq!(store.get(a), None);
+        // Removing twice does nothing
+        assert_eq!(store.remove(a), None);
+        let b = store.insert("b");
+        assert_eq!(b.0, a.0);
+        assert_ne!(b.1, a.1);
+        // The old handle doesn't see the new value
+        assert_eq!(store.get(a), None);
+        assert_eq!(store.remove(a), None);
+        assert_eq!(store.get(b), Some(&"b"));
+        // The slot was only freed once, so the next insert gets a new one
+        let c = store.insert("c");
+        assert_ne!(c.0, b.0);
+    }
+
+    #[test]
+    fn store_replaces_only_live_values() {
+        let mut store = Store::new();
+        let a = store.insert("a");
+        store.replace(a, "a2");
+        assert_eq!(store.get(a), Some(&"a2"));
+        store.remove(a);
+        store.replace(a, "a3");
+        assert_eq!(store.get(a), None);
+        let b = store.insert("b");
+        store.replace(a, "stale");
+        assert_eq!(store.get(b), Some(&"b"));
     }
 }
@@ -0,0 +1,402 @@
+// Inverse kinematics: adjust a joint-local pose, after sampling and before
+// skinning, so that joints reach for or look at points.
+//
+// Targets are in the rig's own space, the same space `Rig::skin` works in; to
+// aim at a point in the world, bring it in through the inverse of the
+// character's instance transform.
+
+use crate::anim::{Bone, Rig};
+use crate::geom::*;
+
+type Frame = cgmath::Decomposed<Vec3, Quat>;
+
+// Where `joint` sits in rig space under the local pose `bones`
+fn model_frame(rig: &Rig, bones: &[Bone], joint: usize) -> Frame {
+    let mut frame = bones[joint].decomposed();
+    let mut parent = rig.joint_parent(joint);
+    while let Some(pi) = parent {
+        frame = bones[pi].decomposed() * frame;
+        parent = rig.joint_parent(pi);
+    }
+    frame
+}
+
+fn model_position(rig: &Rig, bones: &[Bone], joint: usize) -> Vec3 {
+    model_frame(rig, bones, joint).disp
+}
+
+// Turn `joint` by `delta`, given in rig space, about its own origin
+fn rotate_model(rig: &Rig, bones: &mut [Bone], joint: usize, delta: Quat) {
+    let parent = rig
+        .joint_parent(joint)
+        .map_or_else(Quat::one, |pi| model_frame(rig, bones, pi).rot);
+    let local = Quat::from(bones[joint].rotation);
+    bones[joint].rotation = (parent.invert() * delta * parent * local)
+        .normalize()
+        .into();
+}
+
+// The rotation taking direction `from` onto `to`, or `None` if either is too short to say
+fn arc(from: Vec3, to: Vec3) -> Option<Quat> {
+    if from.magnitude2() < 1e-12 || to.magnitude2() < 1e-12 {
+        return None;
+    }
+    let (from, to) = (from.normalize(), to.normalize());
+    let cos = from.dot(to);
+    if cos < -1.0 + 1e-6 {
+        // Opposite ways: any axis at right angles will do, so let cgmath pick one
+        return Some(Quat::between_vectors(from, to));
+    }
+    // Half-way quaternion built from the cross product; unlike between_vectors,
+    // it doesn't round turns of a fraction of a degree down to nothing
+    Some(Quat::from_sv(1.0 + cos, from.cross(to)).normalize())
+}
+
+// The way from `from` to `to`, or `fallback` when the two points coincide
+fn direction(from: Vec3, to: Vec3, fallback: Vec3) -> Vec3 {
+    let d = to - from;
+    if d.magnitude2() < 1e-12 {
+        fallback
+    } else {
+        d.normalize()
+    }
+}
+
+/// A run of joints from a root down to an end effector, each the parent of the next.
+#[derive(Clone, Debug)]
+pub struct IkChain {
+    joints: Vec<usize>,
+}
+
+impl IkChain {
+    /// The chain from `root` down to `end` in `rig`, or `None` if either joint
+    /// is missing or `root` isn't an ancestor of `end`.
+    pub fn new(rig: &Rig, root: &str, end: &str) -> Option<Self> {
+        let root = rig.joint_index(root)?;
+        let mut joints = vec![rig.joint_index(end)?];
+        while *joints.last().unwrap() != root {
+            joints.push(rig.joint_parent(*joints.last().unwrap())?);
+        }
+        joints.reverse();
+        if joints.len() < 2 {
+            return None;
+        }
+        Some(Self { joints })
+    }
+    /// The joints from root to end.
+    pub fn joints(&self) -> &[usize] {
+        &self.joints
+    }
+    fn end(&self) -> usize {
+        *self.joints.last().unwrap()
+    }
+    /// Place the end of a three-joint chain (e.g. hip, knee, ankle) on `target`
+    /// exactly, bending the middle joint towards `pole` if given.  Out of reach
+    /// targets leave the limb straight and pointing at them.  Returns false if
+    /// the chain isn't three joints long or the target was out of reach.
+    pub fn solve_two_bone(
+        &self,
+        rig: &Rig,
+        bones: &mut [Bone],
+        target: Vec3,
+        pole: Option<Vec3>,
+    ) -> bool {
+        let (root, mid, end) = match self.joints.as_slice() {
+            [root, mid, end] => (*root, *mid, *end),
+            _ => return false,
+        };
+        let a = model_position(rig, bones, root);
+        let b = model_position(rig, bones, mid);
+        let c = model_position(rig, bones, end);
+        let (upper, lower) = ((b - a).magnitude(), (c - b).magnitude());
+        let reach = (target - a).magnitude();
+        let eps = 1e-4 * (upper + lower);
+        let want = reach
+            .max((upper - lower).abs() + eps)
+            .min(upper + lower - eps);
+        // Open or close the middle joint until root to end spans the target distance
+        let cos_mid = ((upper * upper + lower * lower - want * want) / (2.0 * upper * lower))
+            .max(-1.0)
+            .min(1.0);
+        let now = (a - b).angle(c - b).0;
+        let mut axis = (a - b).cross(c - b);
+        if axis.magnitude2() < 1e-12 {
+            // A straight limb has no bend plane yet; take one from the pole or any side
+            let side = pole.map_or_else(Vec3::unit_x, |p| p - b);
+            axis = (a - b).cross(side);
+            if axis.magnitude2() < 1e-12 {
+                axis = (a - b).cross(Vec3::unit_y());
+            }
+        }
+        if axis.magnitude2() > 1e-12 {
+            let turn = Quat::from_axis_angle(axis.normalize(), cgmath::Rad(cos_mid.acos() - now));
+            rotate_model(rig, bones, mid, turn);
+        }
+        // Then swing the whole limb from the root onto the target
+        let c = model_position(rig, bones, end);
+        if let Some(swing) = arc(c - a, target - a) {
+            rotate_model(rig, bones, root, swing);
+        }
+        // And twist it about the root-target line so the middle joint faces the pole
+        if let Some(pole) = pole {
+            let along = (target - a).normalize();
+            let b = model_position(rig, bones, mid);
+            let flat = |v: Vec3| v - along * v.dot(along);
+            if let Some(twist) = arc(flat(b - a), flat(pole - a)) {
+                rotate_model(rig, bones, root, twist);
+            }
+        }
+        reach <= upper + lower
+    }
+    /// Cyclic coordinate descent: turn each joint from the end back to the root
+    /// to point the end at `target`, up to `iterations` passes or until it's within
+    /// `tolerance`.  Returns whether it got there.
+    pub fn solve_ccd(
+        &self,
+        rig: &Rig,
+        bones: &mut [Bone],
+        target: Vec3,
+        iterations: usize,
+        tolerance: f32,
+    ) -> bool {
+        let end = self.end();
+        for _ in 0..iterations {
+            if (model_position(rig, bones, end) - target).magnitude() <= tolerance {
+                return true;
+            }
+            for &ji in self.joints[..self.joints.len() - 1].iter().rev() {
+                let at = model_position(rig, bones, ji);
+                let reach = model_position(rig, bones, end);
+                if let Some(turn) = arc(reach - at, target - at) {
+                    rotate_model(rig, bones, ji, turn);
+                }
+            }
+        }
+        (model_position(rig, bones, end) - target).magnitude() <= tolerance
+    }
+    /// FABRIK: move the joints' positions alternately from the end and from the
+    /// root until the end reaches `target`, then turn each joint to match.
+    /// Returns whether the end got within `tolerance`.
+    pub fn solve_fabrik(
+        &self,
+        rig: &Rig,
+        bones: &mut [Bone],
+        target: Vec3,
+        iterations: usize,
+        tolerance: f32,
+    ) -> bool {
+        let mut points: Vec<Vec3> = self
+            .joints
+            .iter()
+            .map(|&ji| model_position(rig, bones, ji))
+            .collect();
+        let lengths: Vec<f32> = points
+            .windows(2)
+            .map(|p| (p[1] - p[0]).magnitude())
+            .collect();
+        // Where each bone points now, for when a pass puts two points on top of each other
+        let rest: Vec<Vec3> = points
+            .windows(2)
+            .map(|p| direction(p[0], p[1], Vec3::unit_y()))
+            .collect();
+        let n = points.len();
+        let base = points[0];
+        if (target - base).magnitude() >= lengths.iter().sum::<f32>() {
+            // Out of reach: stretch straight towards it
+            for i in 1..n {
+                let dir = direction(points[i - 1], target, rest[i - 1]);
+                points[i] = points[i - 1] + dir * lengths[i - 1];
+            }
+        } else {
+            for _ in 0..iterations {
+                if (points[n - 1] - target).magnitude() <= tolerance {
+                    break;
+                }
+                points[n - 1] = target;
+                for i in (0..n - 1).rev() {
+                    let dir = direction(points[i + 1], points[i], -rest[i]);
+                    points[i] = points[i + 1] + dir * lengths[i];
+                }
+                points[0] = base;
+                for i in 1..n {
+                    let dir = direction(points[i - 1], points[i], rest[i - 1]);
+                    points[i] = points[i - 1] + dir * lengths[i - 1];
+                }
+            }
+        }
+        // Turn the joints, root first, so each child lands on its new point
+        for i in 0..n - 1 {
+            let (ji, ci) = (self.joints[i], self.joints[i + 1]);
+            let at = model_position(rig, bones, ji);
+            let child = model_position(rig, bones, ci);
+            if let Some(turn) = arc(child - at, points[i + 1] - at) {
+                rotate_model(rig, bones, ji, turn);
+            }
+        }
+        (model_position(rig, bones, self.end()) - target).magnitude() <= tolerance
+    }
+}
+
+/// Turn `joint` so that `aim`, a direction in the joint's own frame (such as
+/// the way a head faces), points at `target`.  `weight` from 0 to 1 blends from
+/// the pose as it was to fully on target, and the turn stops at `max_angle` radians.
+pub fn look_at(
+    rig: &Rig,
+    bones: &mut [Bone],
+    joint: usize,
+    aim: Vec3,
+    target: Vec3,
+    weight: f32,
+    max_angle: f32,
+) {
+    let frame = model_frame(rig, bones, joint);
+    let turn = match arc(frame.rot.rotate_vector(aim), target - frame.disp) {
+        Some(turn) => turn,
+        None => return,
+    };
+    // A unit quaternion's w is cos(angle/2)
+    let angle = 2.0 * turn.s.max(-1.0).min(1.0).acos();
+    let amount = weight.max(0.0).min(1.0)
+        * if angle > max_angle {
+            max_angle / angle
+        } else {
+            1.0
+        };
+    // slerp, unlike nlerp, turns by exactly `amount` of the angle
+    let turn = Quat::one().slerp(turn, amount);
+    rotate_model(rig, bones, joint, turn);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A straight leg standing up the Y axis: hip at the origin, knee at 1, ankle at 2
+    fn leg() -> Rig {
+        let json = r#"{
+            "asset": { "version": "2.0" },
+            "nodes": [
+                { "name": "hip", "children": [1] },
+                { "name": "knee", "translation": [0, 1, 0], "children": [2] },
+                { "name": "ankle", "translation": [0, 1, 0] }
+            ],
+            "skins": [{ "joints": [0, 1, 2] }]
+        }"#;
+        let g = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+        let skin = g.skins().next().unwrap();
+        Rig::from_gltf(&g, &[], skin).unwrap()
+    }
+
+    fn rest_pose(rig: &Rig) -> Vec<Bone> {
+        let mut bones = vec![Bone::default(); rig.joint_count()];
+        rig.reset(&mut bones);
+        bones
+    }
+
+    fn finite(bones: &[Bone]) -> bool {
+        bones.iter().all(|b| {
+            b.rotation
+                .iter()
+                .chain(b.translation.iter())
+                .all(|x| x.is_finite())
+        })
+    }
+
+    #[test]
+    fn two_bone_reaches_target() {
+        let rig = leg();
+        let mut bones = rest_pose(&rig);
+        let chain = IkChain::new(&rig, "hip", "ankle").unwrap();
+        let target = Vec3::new(1.0, 1.0, 0.0);
+        assert!(chain.solve_two_bone(&rig, &mut bones, target, Some(Vec3::unit_z())));
+        let end = model_position(&rig, &bones, chain.end());
+        assert!((end - target).magnitude() < 1e-3, "{:?}", end);
+    }
+
+    #[test]
+    fn fabrik_reaches_target() {
+        let rig = leg();
+        let mut bones = rest_pose(&rig);
+        let chain = IkChain::new(&rig, "hip", "ankle").unwrap();
+        let target = Vec3::new(0.5, 1.2, 0.5);
+        assert!(chain.solve_fabrik(&rig, &mut bones, target, 20, 1e-3));
+        assert!(finite(&bones));
+    }
+
+    #[test]
+    fn fabrik_target_on_root_stays_finite() {
+        let rig = leg();
+        let mut bones = rest_pose(&rig);
+        let chain = IkChain::new(&rig, "hip", "ankle").unwrap();
+        chain.solve_fabrik(&rig, &mut bones, Vec3::zero(), 20, 1e-3);
+        assert!(finite(&bones));
+    }
+
+    #[test]
+    fn ccd_reaches_target() {
+        let rig = leg();
+        let mut bones = rest_pose(&rig);
+        let chain = IkChain::new(&rig, "hip", "ankle").unwrap();
+        let target = Vec3::new(0.5, 1.2, 0.5);
+        assert!(chain.solve_ccd(&rig, &mut bones, target, 50, 1e-3));
+        let end = model_position(&rig, &bones, chain.end());
+        assert!((end - target).magnitude() <= 1e-3, "{:?}", end);
+        // Out of reach it gives up but stays finite
+        assert!(!chain.solve_ccd(&rig, &mut bones, Vec3::new(0.0, 5.0, 0.0), 10, 1e-3));
+        assert!(finite(&bones));
+    }
+
+    // How far the knee's aim, straight up along the shin at rest, has turned
+    fn knee_turn(rig: &Rig, bones: &[Bone]) -> f32 {
+        let knee = rig.joint_index("knee").unwrap();
+        let aim = model_frame(rig, bones, knee)
+            .rot
+            .rotate_vector(Vec3::unit_y());
+        aim.dot(Vec3::unit_y()).max(-1.0).min(1.0).acos()
+    }
+
+    #[test]
+    fn look_at_turns_fully_within_max_angle() {
+        let rig = leg();
+        let mut bones = rest_pose(&rig);
+        let knee = rig.joint_index("knee").unwrap();
+        // A quarter turn from the knee at (0, 1, 0)
+        let target = Vec3::new(1.0, 1.0, 0.0);
+        look_at(&rig, &mut bones, knee, Vec3::unit_y(), target, 1.0, PI);
+        assert!((knee_turn(&rig, &bones) - 0.5 * PI).abs() < 1e-3);
+    }
+
+    #[test]
+    fn look_at_stops_at_max_angle() {
+        let rig = leg();
+        let mut bones = rest_pose(&rig);
+        let knee = rig.joint_index("knee").unwrap();
+        let target = Vec3::new(1.0, 1.0, 0.0);
+        look_at(&rig, &mut bones, knee, Vec3::unit_y(), target, 1.0, 0.5);
+        assert!((knee_turn(&rig, &bones) - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn look_at_weight_blends() {
+        let rig = leg();
+        let knee = rig.joint_index("knee").unwrap();
+        let target = Vec3::new(1.0, 1.0, 0.0);
+        let mut bones = rest_pose(&rig);
+        look_at(&rig, &mut bones, knee, Vec3::unit_y(), target, 0.5, PI);
+        assert!((knee_turn(&rig, &bones) - 0.25 * PI).abs() < 1e-3);
+        // Weights outside 0..1 are clamped
+        let mut bones = rest_pose(&rig);
+        look_at(&rig, &mut bones, knee, Vec3::unit_y(), target, -1.0, PI);
+        assert!(knee_turn(&rig, &bones) < 1e-3);
+        let mut bones = rest_pose(&rig);
+        look_at(&rig, &mut bones, knee, Vec3::unit_y(), target, 2.0, PI);
+        assert!((knee_turn(&rig, &bones) - 0.5 * PI).abs() < 1e-3);
+    }
+
+    #[test]
+    fn chain_needs_an_ancestor() {
+        let rig = leg();
+        assert!(IkChain::new(&rig, "ankle", "hip").is_none());
+        assert!(IkChain::new(&rig, "hip", "toe").is_none());
+    }
+}
@@ -5,6 +5,9 @@ use winit::{
     platform::run_return::EventLoopExtRunReturn,
 };
 pub mod anim;
+pub mod anim_state;
+pub mod animator;
+pub mod ik;
 pub mod save_load;
 pub mod camera;
 pub mod collision;
@@ -15,9 +18,12 @@ pub mod texture;
 use events::Events;
 pub mod render;
 use render::{InstanceGroups, Render};
+mod shaders;
 pub mod assets;
 use assets::Assets;
 pub mod lights;
+pub mod mixer;
+pub mod music;
 pub mod sound;
 
 pub const DT: f32 = 1.0 / 60.0;
@@ -25,7 +31,7 @@ pub const DT: f32 = 1.0 / 60.0;
 pub trait Game: Sized {
     type StaticData;
     fn start(engine: &mut Engine) -> (Self, Self::StaticData);
-    fn update(&mut self, rules: &Self::StaticData, engine: &mut Engine, sound: &sound::Sound);
+    fn update(&mut self, rules: &Self::StaticData, engine: &mut Engine);
     fn render(&mut self, rules: &Self::StaticData, assets: &Assets, igs: &mut InstanceGroups);
 }
 
@@ -34,10 +40,14 @@ pub struct Engine {
     pub assets: Assets,
     render: Render,
     pub events: Events,
+    pub sound: sound::Sound,
 }
 
 impl Engine {
-    pub fn load_model(&mut self, model: impl AsRef<Path>) -> assets::ModelRef {
+    pub fn load_model(
+        &mut self,
+        model: impl AsRef<Path>,
+    ) -> Result<assets::ModelRef, assets::AssetError> {
         self.assets.load_model(
             &self.render.device,
             &self.render.queue,
@@ -45,24 +55,57 @@ impl Engine {
             model,
         )
     }
-    pub fn load_gltf(
+    /// Like `load_model`, but reports the error and hands back the fallback model instead.
+    pub fn load_model_or_fallback(&mut self, model: impl AsRef<Path>) -> assets::ModelRef {
+        self.load_model(model).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            self.fallback_model()
+        })
+    }
+    pub fn fallback_model(&mut self) -> assets::ModelRef {
+        self.assets.fallback_model(
+            &self.render.device,
+            &self.render.queue,
+            &self.render.texture_layout,
+        )
+    }
+    /// Load an `.obj`, `.gltf` or `.glb` file with everything in it.
+    pub fn load_scene(
         &mut self,
-        gltf: impl AsRef<Path>,
-    ) -> (
-        Vec<assets::ModelRef>,
-        Vec<assets::RigRef>,
-        Vec<assets::AnimRef>,
-    ) {
-        self.assets.load_gltf(
+        file: impl AsRef<Path>,
+    ) -> Result<assets::LoadedScene, assets::AssetError> {
+        self.assets.load_scene(
             &self.render.device,
             &self.render.queue,
             &self.render.texture_layout,
-            gltf,
+            file,
         )
     }
+    /// Start loading a model in the background; see `Assets::poll_model`.
+    pub fn load_model_async(&mut self, model: impl AsRef<Path>) -> assets::PendingModel {
+        self.assets.load_model_async(model)
+    }
+    /// Start loading a scene in the background; see `Assets::poll_scene`.
+    pub fn load_scene_async(&mut self, file: impl AsRef<Path>) -> assets::PendingScene {
+        self.assets.load_scene_async(file)
+    }
+    /// Give back a model from `load_model`; it's unloaded once nothing else holds it.
+    pub fn release_model(&mut self, model: assets::ModelRef) {
+        self.assets.release_model(model);
+    }
+    /// Give back a scene from `load_scene`; it's unloaded once nothing else holds it.
+    pub fn release_scene(&mut self, scene: &assets::LoadedScene) {
+        self.assets.release_scene(scene);
+    }
     pub fn camera_mut(&mut self) -> &mut camera::Camera {
         &mut self.render.camera
     }
+    /// Look through a camera preset, e.g. one from `LoadedScene::cameras`,
+    /// keeping the window's aspect ratio.
+    pub fn set_camera(&mut self, camera: camera::Camera) {
+        let aspect = self.render.camera.aspect;
+        self.render.camera = camera::Camera { aspect, ..camera };
+    }
     pub fn set_ambient(&mut self, amb: f32) {
         self.render.set_ambient(amb);
     }
@@ -80,30 +123,31 @@ pub fn run<R, G: Game<StaticData = R>>(
     let window = window_builder.build(&event_loop).unwrap();
     let assets = Assets::new(asset_root);
     use futures::executor::block_on;
-    let render = block_on(Render::new(&window));
+    // Shaders under the asset root replace the built-in ones and reload when edited.
+    // Without them, debug builds compile the engine's own sources so those can be edited live.
+    let game_shaders = asset_root.join("shaders");
+    let engine_shaders = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+    let shader_dir = if game_shaders.is_dir() {
+        Some(game_shaders)
+    } else if cfg!(debug_assertions) && engine_shaders.is_dir() {
+        Some(engine_shaders)
+    } else {
+        None
+    };
+    let render = block_on(Render::new(&window, shader_dir.as_deref()));
     let events = Events::default();
+    let sound = sound::Sound::new(asset_root);
     let mut engine = Engine {
         assets,
         render,
         events,
+        sound,
         frame: 0,
     };
     let (mut game, rules) = G::start(&mut engine);
     // How many unsimulated frames have we saved up?
     let mut available_time: f32 = 0.0;
     let mut since = Instant::now();
-    // sound stuff
-    let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
-    let mut sink = rodio::SpatialSink::try_new(
-        &handle,
-        [-10.0, 0.0, -10.0], // emitter position
-        [1.0, 0.0, 0.0],   // left ear
-        [-1.0, 0.0, 0.0],  // right ear
-    )
-    .unwrap();
-    // sink.set_volume(5.0);
-    let mut sound = sound::Sound { sink };
-    // sound.add_sound("content/beep3.ogg");
 
     event_loop.run_return(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -137,7 +181,17 @@ pub fn run<R, G: Game<StaticData = R>>(
                 }
             }
             Event::RedrawRequested(_) => {
-                // println!("fds"); more sound
+                engine.render.reload_shaders();
+                engine.assets.reload_changed(
+                    &engine.render.device,
+                    &engine.render.queue,
+                    &engine.render.texture_layout,
+                );
+                engine.assets.finish_loads(
+                    &engine.render.device,
+                    &engine.render.queue,
+                    &engine.render.texture_layout,
+                );
                 match engine.render.render(&mut game, &rules, &mut engine.assets) {
                     Ok(_) => {}
                     // Recreate the swap_chain if lost
@@ -158,7 +212,11 @@ pub fn run<R, G: Game<StaticData = R>>(
             // Eat up one frame worth of time
             available_time -= DT;
 
-            game.update(&rules, &mut engine, &sound);
+            game.update(&rules, &mut engine);
+            if engine.sound.follows_camera() {
+                engine.sound.follow_camera(&engine.render.camera, DT);
+            }
+            engine.sound.update(DT);
 
             engine.events.next_frame();
             engine.frame += 1;
@@ -1,38 +1,76 @@
 use crate::geom::*;
+use cgmath::Rad;
 
+// Laid out to match `struct Light` in shader.frag
 #[derive(Debug, Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
 #[repr(C)]
 pub struct Light {
     pub pos: [f32; 4],
-    // pub dir:[f32;4],
     pub color: [f32; 4],
+    /// The way a spot light points; unused by other lights
+    pub dir: [f32; 4],
+    /// How bright `color` is; falls off with the square of the distance
+    /// except for directional lights
+    pub intensity: f32,
+    /// Cosines of the angles from `dir` where a spot light starts to fade
+    /// and where it's gone completely.  Lights that aren't spots light every
+    /// direction, so their cone is wider than any angle.
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    _pad: f32,
 }
 impl Light {
     pub fn point(pos: Pos3, color: Vec3) -> Self {
         Self {
             pos: [pos.x, pos.y, pos.z, 1.0],
-            // dir:[0.0,0.0,0.0,0.0],
             color: [color.x, color.y, color.z, 0.0],
+            dir: [0.0; 4],
+            intensity: 1.0,
+            cos_inner: -1.0,
+            cos_outer: -2.0,
+            _pad: 0.0,
         }
     }
-    // pub fn directed(dir:Vec3, color:Vec3) -> Self {
-    //     Self {
-    //         pos:[0.0,0.0,0.0,0.0],
-    //         dir:[dir.x,dir.y,dir.z,1.0],
-    //         color:[color.x,color.y,color.z],
-    //     }
-    // }
-    // pub fn spot(pos:Pos3, dir:Vec3, color:Vec3) -> Self {
-    //     Self {
-    //         pos:[pos.x,pos.y,pos.z,1.0],
-    //         dir:[dir.x,dir.y,dir.z,1.0],
-    //         color:[color.x,color.y,color.z],
-    //     }
-    // }
-
+    /// A light infinitely far away shining along `dir`; its `pos.w` is 0.
+    pub fn directional(dir: Vec3, color: Vec3) -> Self {
+        let dir = dir.normalize();
+        Self {
+            pos: [dir.x, dir.y, dir.z, 0.0],
+            ..Self::point(Pos3::origin(), color)
+        }
+    }
+    /// A point light that only shines within `outer` of `dir`, fading out
+    /// from `inner` to `outer`.
+    pub fn spot(pos: Pos3, dir: Vec3, color: Vec3, inner: Rad<f32>, outer: Rad<f32>) -> Self {
+        let dir = dir.normalize();
+        let cos_outer = outer.0.cos();
+        Self {
+            dir: [dir.x, dir.y, dir.z, 0.0],
+            // The shader fades with smoothstep, which needs its edges in order
+            cos_inner: inner.0.cos().max(cos_outer + 1e-4),
+            cos_outer,
+            ..Self::point(pos, color)
+        }
+    }
+    pub fn with_intensity(self, intensity: f32) -> Self {
+        Self { intensity, ..self }
+    }
+    /// A light that adds nothing, for filling unused slots.
+    pub fn off() -> Self {
+        Self::point(Pos3::origin(), Vec3::zero()).with_intensity(0.0)
+    }
+    pub fn is_directional(&self) -> bool {
+        self.pos[3] == 0.0
+    }
+    pub fn is_spot(&self) -> bool {
+        !self.is_directional() && self.cos_outer >= -1.0
+    }
     pub fn position(&self) -> Pos3 {
         Pos3::new(self.pos[0], self.pos[1], self.pos[2])
     }
+    pub fn direction(&self) -> Vec3 {
+        Vec3::new(self.dir[0], self.dir[1], self.dir[2])
+    }
     pub fn color(&self) -> Vec3 {
         Vec3::new(self.color[0], self.color[1], self.color[2])
     }
@@ -0,0 +1,338 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// A group of voices whose volume is set together.  Every bus plays through
+/// `Master`, so turning that down turns everything down.
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    Master,
+    Music,
+    Sfx,
+    Ui,
+    Voice,
+}
+
+impl Bus {
+    pub const ALL: [Bus; 5] = [Bus::Master, Bus::Music, Bus::Sfx, Bus::Ui, Bus::Voice];
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus::Sfx
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BusSettings {
+    #[serde(default = "full_volume")]
+    pub volume: f32,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+fn full_volume() -> f32 {
+    1.0
+}
+
+impl Default for BusSettings {
+    fn default() -> Self {
+        Self {
+            volume: full_volume(),
+            muted: false,
+        }
+    }
+}
+
+/// The player's volume settings, as saved in JSON, e.g.
+///
+/// ```json
+/// { "buses": { "master": { "volume": 0.8 }, "music": { "muted": true } } }
+/// ```
+///
+/// Buses that aren't mentioned play at full volume.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MixerSettings {
+    #[serde(default)]
+    pub buses: BTreeMap<Bus, BusSettings>,
+}
+
+impl MixerSettings {
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// Turns `target` down to `level` while anything is playing on `trigger`,
+/// e.g. to keep music out of the way of dialogue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Duck {
+    pub trigger: Bus,
+    pub target: Bus,
+    /// How loud `target` gets while ducked, as a fraction of its volume
+    pub level: f32,
+    /// Seconds to fade down once `trigger` starts
+    pub attack: f32,
+    /// Seconds to fade back up once `trigger` goes quiet
+    pub release: f32,
+}
+
+// f32s shared with the audio thread without locking
+#[derive(Debug)]
+pub(crate) struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub(crate) fn new(v: f32) -> Self {
+        Self(AtomicU32::new(v.to_bits()))
+    }
+    pub(crate) fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+    pub(crate) fn set(&self, v: f32) {
+        self.0.store(v.to_bits(), Ordering::Relaxed)
+    }
+}
+
+// What each bus finally multiplies its voices by, master and ducking included
+#[derive(Debug)]
+pub(crate) struct BusGains([AtomicF32; 5]);
+
+impl BusGains {
+    pub(crate) fn get(&self, bus: Bus) -> f32 {
+        self.0[bus.index()].get()
+    }
+}
+
+/// Volume and mute for each `Bus`, plus ducking.  Changes reach voices that
+/// are already playing.
+#[derive(Debug)]
+pub struct Mixer {
+    buses: [BusSettings; 5],
+    ducks: Vec<Duck>,
+    // How far each duck has pulled its target down, from 0 (not at all) to 1 (down to its level)
+    ducked: Vec<f32>,
+    gains: Arc<BusGains>,
+}
+
+impl Mixer {
+    /// Every bus at full volume, with the voice bus ducking music.
+    pub fn new() -> Self {
+        let mut mixer = Self {
+            buses: [BusSettings::default(); 5],
+            ducks: vec![],
+            ducked: vec![],
+            gains: Arc::new(BusGains([
+                AtomicF32::new(1.0),
+                AtomicF32::new(1.0),
+                AtomicF32::new(1.0),
+                AtomicF32::new(1.0),
+                AtomicF32::new(1.0),
+            ])),
+        };
+        mixer.add_duck(Duck {
+            trigger: Bus::Voice,
+            target: Bus::Music,
+            level: 0.3,
+            attack: 0.2,
+            release: 0.8,
+        });
+        mixer
+    }
+    pub fn volume(&self, bus: Bus) -> f32 {
+        self.buses[bus.index()].volume
+    }
+    pub fn set_volume(&mut self, bus: Bus, volume: f32) {
+        self.buses[bus.index()].volume = volume.max(0.0);
+        self.publish();
+    }
+    pub fn is_muted(&self, bus: Bus) -> bool {
+        self.buses[bus.index()].muted
+    }
+    pub fn set_muted(&mut self, bus: Bus, muted: bool) {
+        self.buses[bus.index()].muted = muted;
+        self.publish();
+    }
+    /// The current volumes, for saving.
+    pub fn settings(&self) -> MixerSettings {
+        MixerSettings {
+            buses: Bus::ALL
+                .iter()
+                .map(|&bus| (bus, self.buses[bus.index()]))
+                .collect(),
+        }
+    }
+    pub fn apply_settings(&mut self, settings: &MixerSettings) {
+        for &bus in Bus::ALL.iter() {
+            self.buses[bus.index()] = settings.buses.get(&bus).copied().unwrap_or_default();
+        }
+        self.publish();
+    }
+    pub fn add_duck(&mut self, duck: Duck) {
+        self.ducks.push(duck);
+        self.ducked.push(0.0);
+    }
+    pub fn clear_ducks(&mut self) {
+        self.ducks.clear();
+        self.ducked.clear();
+        self.publish();
+    }
+    pub(crate) fn gains(&self) -> Arc<BusGains> {
+        self.gains.clone()
+    }
+    // Fade ducks in or out over `dt` seconds, given which buses have anything playing
+    pub(crate) fn update(&mut self, dt: f32, active: impl Fn(Bus) -> bool) {
+        for (duck, ducked) in self.ducks.iter().zip(self.ducked.iter_mut()) {
+            let (goal, time) = if active(duck.trigger) {
+                (1.0, duck.attack)
+            } else {
+                (0.0, duck.release)
+            };
+            let step = if time > 0.0 { dt / time } else { 1.0 };
+            *ducked = if goal > *ducked {
+                (*ducked + step).min(goal)
+            } else {
+                (*ducked - step).max(goal)
+            };
+        }
+        self.publish();
+    }
+    fn level(&self, bus: Bus) -> f32 {
+        let settings = self.buses[bus.index()];
+        if settings.muted {
+            0.0
+        } else {
+            settings.volume
+        }
+    }
+    fn publish(&self) {
+        let master = self.level(Bus::Master);
+        for &bus in Bus::ALL.iter() {
+            let mut gain = master;
+            if bus != Bus::Master {
+                gain *= self.level(bus);
+            }
+            for (duck, ducked) in self.ducks.iter().zip(self.ducked.iter()) {
+                if duck.target == bus {
+                    gain *= 1.0 + (duck.level - 1.0) * ducked;
+                }
+            }
+            self.gains.0[bus.index()].set(gain);
+        }
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut mixer = Mixer::new();
+        mixer.set_volume(Bus::Master, 0.8);
+        mixer.set_muted(Bus::Music, true);
+        let settings = mixer.settings();
+        let json = serde_json::to_string(&settings).unwrap();
+        assert_eq!(MixerSettings::from_json(&json).unwrap(), settings);
+
+        let path = std::env::temp_dir().join(format!("mixer-{}.json", std::process::id()));
+        settings.save(&path).unwrap();
+        let loaded = MixerSettings::load(&path);
+        let _ = std::fs::remove_file(&path);
+        let mut other = Mixer::new();
+        other.apply_settings(&loaded.unwrap());
+        assert_eq!(other.volume(Bus::Master), 0.8);
+        assert!(other.is_muted(Bus::Music));
+        assert!(!other.is_muted(Bus::Sfx));
+    }
+
+    #[test]
+    fn missing_buses_play_at_full_volume() {
+        let settings =
+            MixerSettings::from_json(r#"{ "buses": { "sfx": { "volume": 0.5 } } }"#).unwrap();
+        let mut mixer = Mixer::new();
+        mixer.set_volume(Bus::Ui, 0.1);
+        mixer.apply_settings(&settings);
+        assert_eq!(mixer.volume(Bus::Sfx), 0.5);
+        assert_eq!(mixer.volume(Bus::Ui), 1.0);
+        assert_eq!(
+            MixerSettings::from_json("{}").unwrap(),
+            MixerSettings::default()
+        );
+    }
+
+    #[test]
+    fn gains_include_master_and_mute() {
+        let mut mixer = Mixer::new();
+        let gains = mixer.gains();
+        mixer.set_volume(Bus::Master, 0.5);
+        mixer.set_volume(Bus::Sfx, 0.5);
+        assert!(close(gains.get(Bus::Master), 0.5));
+        assert!(close(gains.get(Bus::Sfx), 0.25));
+        assert!(close(gains.get(Bus::Ui), 0.5));
+        mixer.set_muted(Bus::Master, true);
+        assert_eq!(gains.get(Bus::Sfx), 0.0);
+    }
+
+    #[test]
+    fn ducking_fades_down_and_back() {
+        // The default duck: voice takes music down to 0.3 over 0.2s, back over 0.8s
+        let mut mixer = Mixer::new();
+        let gains = mixer.gains();
+        let talking = |bus| bus == Bus::Voice;
+        mixer.update(0.1, talking);
+        assert!(close(gains.get(Bus::Music), 1.0 + (0.3 - 1.0) * 0.5));
+        mixer.update(0.5, talking);
+        assert!(close(gains.get(Bus::Music), 0.3));
+        // Other buses aren't touched
+        assert_eq!(gains.get(Bus::Sfx), 1.0);
+        let quiet = |_| false;
+        mixer.update(0.4, quiet);
+        assert!(close(gains.get(Bus::Music), 1.0 + (0.3 - 1.0) * 0.5));
+        mixer.update(1.0, quiet);
+        assert!(close(gains.get(Bus::Music), 1.0));
+    }
+
+    #[test]
+    fn instant_duck() {
+        let mut mixer = Mixer::new();
+        mixer.clear_ducks();
+        mixer.add_duck(Duck {
+            trigger: Bus::Ui,
+            target: Bus::Sfx,
+            level: 0.0,
+            attack: 0.0,
+            release: 0.0,
+        });
+        mixer.update(0.01, |bus| bus == Bus::Ui);
+        assert_eq!(mixer.gains().get(Bus::Sfx), 0.0);
+        mixer.update(0.01, |_| false);
+        assert_eq!(mixer.gains().get(Bus::Sfx), 1.0);
+    }
+}
@@ -1,8 +1,10 @@
+use crate::assets::{AssetError, AssetErrorKind};
 use crate::geom::*;
+use crate::render::MORPH_MAX;
 use crate::texture;
-use anyhow::*;
 use std::ops::Range;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use wgpu::util::DeviceExt;
 
 pub trait Vertex {
@@ -15,7 +17,7 @@ pub struct ModelVertex {
     position: [f32; 3],
     tex_coords: [f32; 2],
     normal: [f32; 3],
-    bone_ids: u32, // 32 bits, fits into last slot of previous line
+    bone_ids: [u16; 4], // 64 bits, so rigs can have more than 255 joints
     // Not relevant for static geometry, wasteful!
     // But, this means we just need one layout...
     bone_weights: [f32; 4], // 32*4 bits
@@ -46,10 +48,10 @@ impl Vertex for ModelVertex {
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
                     shader_location: 3,
-                    format: wgpu::VertexFormat::Uint,
+                    format: wgpu::VertexFormat::Ushort4,
                 },
                 wgpu::VertexAttribute {
-                    offset: (mem::size_of::<[f32; 8]>() + mem::size_of::<u32>())
+                    offset: (mem::size_of::<[f32; 8]>() + mem::size_of::<[u16; 4]>())
                         as wgpu::BufferAddress,
                     shader_location: 4,
                     format: wgpu::VertexFormat::Float4,
@@ -59,6 +61,15 @@ impl Vertex for ModelVertex {
     }
 }
 
+/// How far one vertex moves under one morph target at full weight.  The w
+/// components are padding for the shader.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MorphDelta {
+    pub position: [f32; 4],
+    pub normal: [f32; 4],
+}
+
 pub struct Material {
     pub name: String,
     pub diffuse_texture: texture::Texture,
@@ -66,12 +77,101 @@ pub struct Material {
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        name: impl Into<String>,
+        diffuse_texture: texture::Texture,
+    ) -> Self {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+            ],
+            label: None,
+        });
+        Self {
+            name: name.into(),
+            diffuse_texture,
+            bind_group,
+        }
+    }
+    pub fn fallback(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        Self::new(
+            device,
+            layout,
+            "Default Material",
+            texture::Texture::fallback(device, queue),
+        )
+    }
+}
+
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    // Target and vertex counts followed by every target's deltas, for shader_bones.vert
+    pub morph_buffer: Option<wgpu::Buffer>,
+}
+
+impl Mesh {
+    fn new(
+        device: &wgpu::Device,
+        name: &str,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        morph_targets: &[Vec<MorphDelta>],
+        material: usize,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Index Buffer", name)),
+            contents: bytemuck::cast_slice(indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        let morph_buffer = if morph_targets.is_empty() {
+            None
+        } else {
+            let header = [morph_targets.len() as u32, vertices.len() as u32, 0, 0];
+            let mut contents = bytemuck::cast_slice(&header).to_vec();
+            for target in morph_targets {
+                contents.extend_from_slice(bytemuck::cast_slice(target));
+            }
+            Some(
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{:?} Morph Buffer", name)),
+                    contents: &contents,
+                    usage: wgpu::BufferUsage::STORAGE,
+                }),
+            )
+        };
+        Self {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: indices.len() as u32,
+            material,
+            morph_buffer,
+        }
+    }
 }
 
 fn convert_mag_filter(f: Option<gltf::texture::MagFilter>) -> wgpu::FilterMode {
@@ -105,50 +205,119 @@ fn convert_wrap(f: gltf::texture::WrappingMode) -> wgpu::AddressMode {
     }
 }
 
-pub struct Model {
-    pub meshes: Vec<Mesh>,
-    pub materials: Vec<Material>,
+fn convert_image(data: gltf::image::Data) -> Option<image::DynamicImage> {
+    let gltf::image::Data {
+        pixels,
+        format,
+        width,
+        height,
+    } = data;
+    use gltf::image::Format;
+    use image::DynamicImage as DI;
+    Some(match format {
+        Format::R8 => DI::ImageLuma8(image::ImageBuffer::from_raw(width, height, pixels)?),
+        Format::R8G8 => DI::ImageLumaA8(image::ImageBuffer::from_raw(width, height, pixels)?),
+        Format::R8G8B8 => DI::ImageRgb8(image::ImageBuffer::from_raw(width, height, pixels)?),
+        Format::R8G8B8A8 => DI::ImageRgba8(image::ImageBuffer::from_raw(width, height, pixels)?),
+        Format::B8G8R8 => DI::ImageBgr8(image::ImageBuffer::from_raw(width, height, pixels)?),
+        Format::B8G8R8A8 => DI::ImageBgra8(image::ImageBuffer::from_raw(width, height, pixels)?),
+        // Format::R16 => DI::ImageLuma16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap()),
+        // Format::R16G16 => DI::ImageLumaA16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap()),
+        // Format::R16G16B16 => DI::ImageRgb16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap()),
+        // Format::R16G16B16A16 => DI::ImageRgba16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap())
+        _ => return None,
+    })
 }
 
-impl Model {
-    pub fn load_obj(
+/// A material's texture and sampler settings, decoded but not yet on the GPU.
+pub struct MaterialData {
+    pub name: String,
+    // None means use the fallback texture
+    pub diffuse: Option<image::DynamicImage>,
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mag_filter: wgpu::FilterMode,
+}
+
+impl MaterialData {
+    fn fallback() -> Self {
+        Self {
+            name: "Default Material".to_string(),
+            diffuse: None,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            min_filter: wgpu::FilterMode::Linear,
+            mag_filter: wgpu::FilterMode::Linear,
+        }
+    }
+    fn upload(
+        self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
-        path: &Path,
-    ) -> Result<Self> {
-        let (obj_models, obj_materials) = tobj::load_obj(path, true)?;
+    ) -> Material {
+        let diffuse_texture = match &self.diffuse {
+            Some(img) => texture::Texture::from_image(
+                device,
+                queue,
+                img,
+                Some(&self.name),
+                self.address_mode_u,
+                self.address_mode_v,
+                wgpu::AddressMode::default(),
+                self.min_filter,
+                self.mag_filter,
+            )
+            .unwrap_or_else(|_| texture::Texture::fallback(device, queue)),
+            None => texture::Texture::fallback(device, queue),
+        };
+        Material::new(device, layout, self.name, diffuse_texture)
+    }
+}
+
+/// Vertices and indices for one mesh, ready to be copied into GPU buffers.
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    /// Each morph target's deltas, one per vertex
+    pub morph_targets: Vec<Vec<MorphDelta>>,
+    pub material: usize,
+}
+
+/// Everything in a model file decoded into memory.  This is the part of loading
+/// that can happen off the render thread; `upload` finishes the job.
+pub struct ModelData {
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+    /// Morph target weights to use when nothing animates them
+    pub morph_weights: Vec<f32>,
+}
+
+impl ModelData {
+    pub fn load_obj(path: &Path) -> Result<Self, AssetError> {
+        let (obj_models, obj_materials) = tobj::load_obj(path, true)
+            .map_err(|e| AssetError::new(path, AssetErrorKind::Obj(e)))?;
 
         // We're assuming that the texture files are stored with the obj file
-        let containing_folder = path.parent().context("Directory has no parent")?;
+        let containing_folder = path.parent().unwrap_or_else(|| Path::new(""));
 
         let mut materials = Vec::new();
         for mat in obj_materials {
-            let diffuse_path = mat.diffuse_texture;
-            let diffuse_texture =
-                texture::Texture::load(device, queue, containing_folder.join(diffuse_path))?;
-
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                    },
-                ],
-                label: None,
-            });
-
-            materials.push(Material {
+            let diffuse_path = containing_folder.join(mat.diffuse_texture);
+            let diffuse = image::open(&diffuse_path)
+                .map_err(|e| eprintln!("{:?}: {}, using fallback texture", diffuse_path, e))
+                .ok();
+            materials.push(MaterialData {
                 name: mat.name,
-                diffuse_texture,
-                bind_group,
+                diffuse,
+                ..MaterialData::fallback()
             });
         }
+        if materials.is_empty() {
+            materials.push(MaterialData::fallback());
+        }
 
         let mut meshes = Vec::new();
         for m in obj_models {
@@ -160,189 +329,111 @@ impl Model {
                         m.mesh.positions[i * 3 + 1],
                         m.mesh.positions[i * 3 + 2],
                     ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                    bone_ids: 0,
+                    tex_coords: if m.mesh.texcoords.is_empty() {
+                        [0.0, 0.0]
+                    } else {
+                        [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]]
+                    },
+                    normal: if m.mesh.normals.is_empty() {
+                        [0.0, 1.0, 0.0]
+                    } else {
+                        [
+                            m.mesh.normals[i * 3],
+                            m.mesh.normals[i * 3 + 1],
+                            m.mesh.normals[i * 3 + 2],
+                        ]
+                    },
+                    bone_ids: [0; 4],
                     bone_weights: [1.0, 0.0, 0.0, 0.0],
                 });
             }
-
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsage::INDEX,
-            });
-
-            meshes.push(Mesh {
+            let material = m
+                .mesh
+                .material_id
+                .filter(|mi| *mi < materials.len())
+                .unwrap_or(0);
+            meshes.push(MeshData {
                 name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+                vertices,
+                indices: m.mesh.indices,
+                morph_targets: vec![],
+                material,
             });
         }
 
-        Ok(Self { meshes, materials })
+        Ok(Self {
+            meshes,
+            materials,
+            morph_weights: vec![],
+        })
     }
 
-    pub fn load(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        layout: &wgpu::BindGroupLayout,
-        model: impl AsRef<Path>,
-    ) -> Result<Self> {
+    pub fn load(model: impl AsRef<Path>) -> Result<Self, AssetError> {
         let p = model.as_ref();
-        match p.extension().map(|osstr| osstr.to_str().unwrap()) {
-            Some("obj") => Self::load_obj(device, queue, layout, p),
-            _ => panic!("Unsupported model format {:?}", p),
+        match p.extension().and_then(|osstr| osstr.to_str()) {
+            Some("obj") => Self::load_obj(p),
+            _ => Err(AssetError::new(p, AssetErrorKind::UnsupportedFormat)),
         }
     }
+
     pub fn from_gltf(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        layout: &wgpu::BindGroupLayout,
         g: &gltf::Document,
         bufs: &[gltf::buffer::Data],
         images: &[gltf::image::Data],
         mesh: gltf::Mesh,
-    ) -> Self {
+    ) -> Result<Self, AssetErrorKind> {
         let mut materials: Vec<_> = g
             .materials()
             .map(|mat| {
-                let diffuse = mat
-                    .pbr_metallic_roughness()
-                    .base_color_texture()
-                    .unwrap()
-                    .texture();
-                let gltf::image::Data {
-                    pixels,
-                    format,
-                    width,
-                    height,
-                } = images[diffuse.source().index()].clone();
+                let name = mat.name().unwrap_or("").to_string();
+                let diffuse = match mat.pbr_metallic_roughness().base_color_texture() {
+                    Some(info) => info.texture(),
+                    None => {
+                        return MaterialData {
+                            name,
+                            ..MaterialData::fallback()
+                        }
+                    }
+                };
                 let sam = diffuse.sampler();
-                use gltf::image::Format;
-                use image::DynamicImage as DI;
-                let diffuse_texture = texture::Texture::from_image(
-                    device,
-                    queue,
-                    &match format {
-                        Format::R8 => DI::ImageLuma8(
-                            image::ImageBuffer::from_raw(width, height, pixels).unwrap(),
-                        ),
-                        Format::R8G8 => DI::ImageLumaA8(
-                            image::ImageBuffer::from_raw(width, height, pixels).unwrap(),
-                        ),
-                        Format::R8G8B8 => DI::ImageRgb8(
-                            image::ImageBuffer::from_raw(width, height, pixels).unwrap(),
-                        ),
-                        Format::R8G8B8A8 => DI::ImageRgba8(
-                            image::ImageBuffer::from_raw(width, height, pixels).unwrap(),
-                        ),
-                        Format::B8G8R8 => DI::ImageBgr8(
-                            image::ImageBuffer::from_raw(width, height, pixels).unwrap(),
-                        ),
-                        Format::B8G8R8A8 => DI::ImageBgra8(
-                            image::ImageBuffer::from_raw(width, height, pixels).unwrap(),
-                        ),
-                        // Format::R16 => DI::ImageLuma16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap()),
-                        // Format::R16G16 => DI::ImageLumaA16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap()),
-                        // Format::R16G16B16 => DI::ImageRgb16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap()),
-                        // Format::R16G16B16A16 => DI::ImageRgba16(image::ImageBuffer::from_raw(diffuse_image.width, diffuse_image.height, diffuse_image.pixels).unwrap())
-                        _ => panic!("This is just ridiculous"),
-                    },
-                    diffuse.name(),
-                    convert_wrap(sam.wrap_s()),
-                    convert_wrap(sam.wrap_t()),
-                    wgpu::AddressMode::default(),
-                    convert_min_filter(sam.min_filter()),
-                    convert_mag_filter(sam.mag_filter()),
-                )
-                .unwrap();
-                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                    layout,
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                        },
-                    ],
-                    label: None,
-                });
-                Material {
-                    name: mat.name().unwrap_or("").to_string(),
-                    diffuse_texture,
-                    bind_group,
+                let img = convert_image(images[diffuse.source().index()].clone());
+                if img.is_none() {
+                    eprintln!("Unsupported image format in {:?}, using fallback", name);
+                }
+                MaterialData {
+                    name,
+                    diffuse: img,
+                    address_mode_u: convert_wrap(sam.wrap_s()),
+                    address_mode_v: convert_wrap(sam.wrap_t()),
+                    min_filter: convert_min_filter(sam.min_filter()),
+                    mag_filter: convert_mag_filter(sam.mag_filter()),
                 }
             })
             .collect();
         if materials.len() == 0 {
-            // TODO if empty use a default material
-            materials.push({
-                use image::DynamicImage as DI;
-                let diffuse_texture = texture::Texture::from_image(
-                    device,
-                    queue,
-                    &DI::ImageRgb8(image::ImageBuffer::from_pixel(
-                        16,
-                        16,
-                        image::Rgb([255, 0, 255]),
-                    )),
-                    Some("Default Material"),
-                    wgpu::AddressMode::Repeat,
-                    wgpu::AddressMode::Repeat,
-                    wgpu::AddressMode::default(),
-                    wgpu::FilterMode::Nearest,
-                    wgpu::FilterMode::Nearest,
-                )
-                .unwrap();
-                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                    layout,
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                        },
-                    ],
-                    label: None,
-                });
-                Material {
-                    name: "Default Material".to_string(),
-                    diffuse_texture,
-                    bind_group,
-                }
-            })
+            materials.push(MaterialData::fallback());
         }
         let mut meshes = Vec::new();
         for prim in mesh.primitives() {
             let reader = prim.reader(|b| Some(&bufs[b.index()]));
             // positions, normals,tex_coords, weights, joints
-            let positions: Vec<_> = reader.read_positions().unwrap().collect();
+            let positions: Vec<_> = reader
+                .read_positions()
+                .ok_or_else(|| AssetErrorKind::Invalid("primitive has no positions".into()))?
+                .collect();
             // indices
             let indices: Vec<u32> = match reader.read_indices() {
-                Some(gltf::mesh::util::ReadIndices::U8(idxs)) => idxs.map(|i| i as u32).collect(),
-                Some(gltf::mesh::util::ReadIndices::U16(idxs)) => idxs.map(|i| i as u32).collect(),
-                Some(gltf::mesh::util::ReadIndices::U32(idxs)) => idxs.map(|i| i as u32).collect(),
+                Some(idxs) => idxs.into_u32().collect(),
                 // TODO be smarter about indices
                 None => (0..(positions.len() as u32)).collect(),
             };
+            if let Some(bad) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+                return Err(AssetErrorKind::Invalid(format!(
+                    "index {} out of range for {} vertices",
+                    bad,
+                    positions.len()
+                )));
+            }
             let normal: Vec<[f32; 3]> = reader
                 .read_normals()
                 .map(|nr| nr.collect())
@@ -371,27 +462,79 @@ impl Model {
             // assumption: only one set of each of tex coords, weights, joints
             let tex_coords = match reader.read_tex_coords(0) {
                 None => vec![[0.0, 0.0]; positions.len()],
-                Some(gltf::mesh::util::ReadTexCoords::F32(tcs)) => tcs.collect(),
-                _ => panic!("Unsupported tex coord format"),
+                Some(tcs) => tcs.into_f32().collect(),
             };
+            // Unskinned meshes follow joint 0, so they can still be drawn with
+            // render_anim to get their morph targets
             let bone_weights = match reader.read_weights(0) {
-                None => vec![[0.0; 4]; positions.len()],
-                Some(gltf::mesh::util::ReadWeights::F32(wts)) => wts.collect(),
-                _ => panic!("Unsupported weight format"),
+                None => vec![[1.0, 0.0, 0.0, 0.0]; positions.len()],
+                Some(wts) => wts.into_f32().collect(),
             };
+            // Unused influences have zero weight, so joint 0 is as good as any
             let joints = match reader.read_joints(0) {
-                None => vec![[255; 4]; positions.len()],
-                Some(gltf::mesh::util::ReadJoints::U8(js)) => js.collect(),
-                Some(gltf::mesh::util::ReadJoints::U16(js)) => js
-                    .map(|j| {
-                        assert!(j[0] <= 255);
-                        assert!(j[1] <= 255);
-                        assert!(j[2] <= 255);
-                        assert!(j[3] <= 255);
-                        [j[0] as u8, j[1] as u8, j[2] as u8, j[3] as u8]
-                    })
-                    .collect(),
+                None => vec![[0; 4]; positions.len()],
+                Some(js) => js.into_u16().collect(),
             };
+            // Every attribute needs one entry per vertex, or zip would quietly drop vertices
+            for (name, len) in [
+                ("normals", normal.len()),
+                ("texture coordinates", tex_coords.len()),
+                ("weights", bone_weights.len()),
+                ("joints", joints.len()),
+            ]
+            .iter()
+            {
+                if *len != positions.len() {
+                    return Err(AssetErrorKind::Invalid(format!(
+                        "{} {} for {} vertices",
+                        len,
+                        name,
+                        positions.len()
+                    )));
+                }
+            }
+            let mut morph_targets = vec![];
+            for (mi, (dps, dns, _)) in reader.read_morph_targets().enumerate() {
+                if mi == MORPH_MAX {
+                    eprintln!(
+                        "{:?} has more than {} morph targets, ignoring the rest",
+                        mesh.name().unwrap_or(""),
+                        MORPH_MAX
+                    );
+                    break;
+                }
+                let mut deltas = vec![
+                    MorphDelta {
+                        position: [0.0; 4],
+                        normal: [0.0; 4],
+                    };
+                    positions.len()
+                ];
+                let count = |n: usize| {
+                    if n == positions.len() {
+                        Ok(())
+                    } else {
+                        Err(AssetErrorKind::Invalid(
+                            "morph target vertex count mismatch".into(),
+                        ))
+                    }
+                };
+                if let Some(dps) = dps {
+                    let dps: Vec<_> = dps.collect();
+                    count(dps.len())?;
+                    for (d, [x, y, z]) in deltas.iter_mut().zip(dps) {
+                        d.position = [x, y, z, 0.0];
+                    }
+                }
+                if let Some(dns) = dns {
+                    let dns: Vec<_> = dns.collect();
+                    count(dns.len())?;
+                    for (d, [x, y, z]) in deltas.iter_mut().zip(dns) {
+                        d.normal = [x, y, z, 0.0];
+                    }
+                }
+                morph_targets.push(deltas);
+            }
             let vertices: Vec<_> = positions
                 .into_iter()
                 .zip(tex_coords.into_iter())
@@ -402,37 +545,151 @@ impl Model {
                     position: p,
                     tex_coords: tc,
                     normal: n,
-                    bone_ids: {
-                        ((bi[0] as u32) << 24)
-                            | ((bi[1] as u32) << 16)
-                            | ((bi[2] as u32) << 8)
-                            | (bi[3] as u32)
-                    },
+                    bone_ids: bi,
                     bone_weights: bw,
                 })
                 .collect();
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", mesh.name().unwrap_or(""))),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", mesh.name().unwrap_or(""))),
-                contents: bytemuck::cast_slice(&indices),
-                usage: wgpu::BufferUsage::INDEX,
-            });
-
-            // make buffers
-            meshes.push(Mesh {
+            meshes.push(MeshData {
                 name: mesh.name().unwrap_or("").to_string(),
-                material: prim.material().index().unwrap_or(0),
-                vertex_buffer,
-                index_buffer,
-                num_elements: indices.len() as u32,
+                vertices,
+                indices,
+                morph_targets,
+                material: prim
+                    .material()
+                    .index()
+                    .filter(|mi| *mi < materials.len())
+                    .unwrap_or(0),
             })
         }
-        Model { materials, meshes }
+        let morph_weights = mesh
+            .weights()
+            .map(|ws| ws.iter().copied().take(MORPH_MAX).collect())
+            .unwrap_or_default();
+        Ok(Self {
+            materials,
+            meshes,
+            morph_weights,
+        })
+    }
+
+    /// Create the GPU buffers and textures.  Must run on the thread that owns the device.
+    pub fn upload(
+        self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Model {
+        Model {
+            meshes: self
+                .meshes
+                .iter()
+                .map(|m| {
+                    Mesh::new(
+                        device,
+                        &m.name,
+                        &m.vertices,
+                        &m.indices,
+                        &m.morph_targets,
+                        m.material,
+                    )
+                })
+                .collect(),
+            materials: self
+                .materials
+                .into_iter()
+                .map(|m| m.upload(device, queue, layout))
+                .collect(),
+            morph_weights: self.morph_weights,
+            upload_id: NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+pub struct Model {
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<Material>,
+    /// Morph target weights to use when nothing animates them
+    pub morph_weights: Vec<f32>,
+    // Different for every upload, so the renderer can tell a reloaded model
+    // from the one it replaced
+    pub(crate) upload_id: u64,
+}
+
+impl Model {
+    pub fn load_obj(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: &Path,
+    ) -> Result<Self, AssetError> {
+        Ok(ModelData::load_obj(path)?.upload(device, queue, layout))
+    }
+
+    pub fn load(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        model: impl AsRef<Path>,
+    ) -> Result<Self, AssetError> {
+        Ok(ModelData::load(model)?.upload(device, queue, layout))
+    }
+    /// A unit cube with the fallback material, to stand in for models that failed to load.
+    pub fn fallback(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        // (normal, u, v) with u x v = normal so each face winds counter-clockwise
+        let faces = [
+            (Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z()),
+            (-Vec3::unit_x(), Vec3::unit_z(), Vec3::unit_y()),
+            (Vec3::unit_y(), Vec3::unit_z(), Vec3::unit_x()),
+            (-Vec3::unit_y(), Vec3::unit_x(), Vec3::unit_z()),
+            (Vec3::unit_z(), Vec3::unit_x(), Vec3::unit_y()),
+            (-Vec3::unit_z(), Vec3::unit_y(), Vec3::unit_x()),
+        ];
+        let mut vertices = vec![];
+        let mut indices: Vec<u32> = vec![];
+        for (n, u, v) in faces.iter() {
+            let base = vertices.len() as u32;
+            for (su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter() {
+                let p = (*n + *u * *su + *v * *sv) * 0.5;
+                vertices.push(ModelVertex {
+                    position: p.into(),
+                    tex_coords: [(su + 1.0) / 2.0, (sv + 1.0) / 2.0],
+                    normal: (*n).into(),
+                    bone_ids: [0; 4],
+                    bone_weights: [1.0, 0.0, 0.0, 0.0],
+                });
+            }
+            indices.extend([base, base + 1, base + 2, base, base + 2, base + 3].iter());
+        }
+        ModelData {
+            meshes: vec![MeshData {
+                name: "Fallback".to_string(),
+                vertices,
+                indices,
+                morph_targets: vec![],
+                material: 0,
+            }],
+            materials: vec![MaterialData::fallback()],
+            morph_weights: vec![],
+        }
+        .upload(device, queue, layout)
+    }
+    pub fn from_gltf(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        g: &gltf::Document,
+        bufs: &[gltf::buffer::Data],
+        images: &[gltf::image::Data],
+        mesh: gltf::Mesh,
+    ) -> Result<Self, AssetErrorKind> {
+        Ok(ModelData::from_gltf(g, bufs, images, mesh)?.upload(device, queue, layout))
     }
 }
 
@@ -0,0 +1,434 @@
+use crate::assets::{AssetError, AssetErrorKind};
+use crate::mixer::{AtomicF32, Bus, BusGains};
+use rodio::{Decoder, OutputStreamHandle, Source};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Frames handed from the decoding thread to the audio thread at a time
+const CHUNK_FRAMES: usize = 1024;
+// Seconds decoded ahead even when nothing needs to see the end coming, so a
+// slow disk or a busy decoding thread doesn't starve the audio thread
+const MIN_LOOKAHEAD: f32 = 0.5;
+
+/// A piece of music, streamed from disk as it plays.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    /// Relative to the asset root
+    pub path: PathBuf,
+    /// Once playback reaches `loop_end` (or the end of the file), it jumps
+    /// back to here, in seconds, and keeps looping until the music changes
+    #[serde(default)]
+    pub loop_start: Option<f32>,
+    #[serde(default)]
+    pub loop_end: Option<f32>,
+}
+
+impl Track {
+    /// Play `path` through once.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            loop_start: None,
+            loop_end: None,
+        }
+    }
+    /// Loop the whole track.
+    pub fn looped(path: impl AsRef<Path>) -> Self {
+        Self {
+            loop_start: Some(0.0),
+            ..Self::new(path)
+        }
+    }
+    fn open(&self) -> Result<Decoder<BufReader<File>>, AssetError> {
+        let file = File::open(&self.path)
+            .map_err(|e| AssetError::new(&self.path, AssetErrorKind::Io(e)))?;
+        Decoder::new(BufReader::new(file))
+            .map_err(|e| AssetError::new(&self.path, AssetErrorKind::Audio(e)))
+    }
+}
+
+/// Tracks to play one after another, crossfading `fade` seconds between them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Playlist {
+    pub tracks: Vec<Track>,
+    /// Start over after the last track
+    #[serde(default)]
+    pub repeat: bool,
+    #[serde(default = "default_fade")]
+    pub fade: f32,
+}
+
+fn default_fade() -> f32 {
+    2.0
+}
+
+impl Playlist {
+    pub fn new(tracks: Vec<Track>) -> Self {
+        Self {
+            tracks,
+            repeat: false,
+            fade: default_fade(),
+        }
+    }
+}
+
+// Shared by a stream's decoding thread, its source on the audio thread, and the player
+#[derive(Debug)]
+struct StreamState {
+    gain: AtomicF32,
+    stopped: AtomicBool,
+    // Frames the audio thread has played
+    played: AtomicUsize,
+    // Frames in the whole stream, once the decoding thread reaches the end
+    total: AtomicUsize,
+    finished: AtomicBool,
+}
+
+// Decode `track` ahead of playback, looping its section, until the source goes away
+fn decode(
+    track: Track,
+    decoder: Decoder<BufReader<File>>,
+    chunks: SyncSender<Vec<f32>>,
+    state: Arc<StreamState>,
+) {
+    let channels = decoder.channels() as usize;
+    let rate = decoder.sample_rate() as f32;
+    let mut samples = decoder.convert_samples::<f32>();
+    let loop_start = track.loop_start.map(|s| (s.max(0.0) * rate) as usize);
+    let loop_end = track.loop_end.map(|s| (s * rate) as usize);
+    // Frames into the file, and frames sent in all
+    let mut frame = 0;
+    let mut sent = 0;
+    let mut chunk = Vec::with_capacity(CHUNK_FRAMES * channels);
+    loop {
+        let at_loop_end = loop_end.map_or(false, |end| frame >= end);
+        let next = if at_loop_end { None } else { samples.next() };
+        match next {
+            Some(s) => {
+                chunk.push(s);
+                if chunk.len() % channels == 0 {
+                    frame += 1;
+                }
+            }
+            None => match loop_start {
+                // Back to the start of the section, skipping there on this thread
+                Some(start) if start < frame => {
+                    samples = match track.open() {
+                        Ok(d) => d.convert_samples(),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            break;
+                        }
+                    };
+                    samples.by_ref().take(start * channels).for_each(drop);
+                    frame = start;
+                }
+                _ => break,
+            },
+        }
+        if chunk.len() == CHUNK_FRAMES * channels {
+            sent += CHUNK_FRAMES;
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_FRAMES * channels));
+            if chunks.send(full).is_err() {
+                // Nobody's listening any more
+                return;
+            }
+        }
+    }
+    chunk.truncate(chunk.len() - chunk.len() % channels);
+    sent += chunk.len() / channels;
+    state.total.store(sent, Ordering::Relaxed);
+    if !chunk.is_empty() {
+        let _ = chunks.send(chunk);
+    }
+}
+
+// Plays chunks from a decoding thread on the music bus
+struct StreamSource {
+    chunks: Receiver<Vec<f32>>,
+    chunk: Vec<f32>,
+    pos: usize,
+    channels: u16,
+    sample_rate: u32,
+    gain: f32,
+    state: Arc<StreamState>,
+    buses: Arc<BusGains>,
+}
+
+impl Iterator for StreamSource {
+    type Item = f32;
+    fn next(&mut self) -> Option<f32> {
+        if self.pos == self.chunk.len() {
+            if self.state.stopped.load(Ordering::Relaxed) {
+                self.state.finished.store(true, Ordering::Relaxed);
+                return None;
+            }
+            self.pos = 0;
+            self.chunk = match self.chunks.try_recv() {
+                Ok(chunk) => {
+                    self.state
+                        .played
+                        .fetch_add(chunk.len() / self.channels as usize, Ordering::Relaxed);
+                    chunk
+                }
+                // The decoder fell behind; play a frame of silence while it catches up
+                Err(TryRecvError::Empty) => vec![0.0; self.channels as usize],
+                Err(TryRecvError::Disconnected) => {
+                    self.state.finished.store(true, Ordering::Relaxed);
+                    return None;
+                }
+            };
+            self.gain = self.state.gain.get() * self.buses.get(Bus::Music);
+        }
+        let s = self.chunk[self.pos] * self.gain;
+        self.pos += 1;
+        Some(s)
+    }
+}
+
+impl Source for StreamSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// A track the player has started, and where its fade is at
+#[derive(Debug)]
+struct Stream {
+    state: Arc<StreamState>,
+    sample_rate: u32,
+    level: f32,
+    // Change in level per second; positive fades in, negative fades out
+    fade: f32,
+}
+
+impl Stream {
+    // Seconds left to play, once the decoder knows
+    fn remaining(&self) -> Option<f32> {
+        let total = self.state.total.load(Ordering::Relaxed);
+        if total == usize::MAX {
+            return None;
+        }
+        let played = self.state.played.load(Ordering::Relaxed);
+        Some(total.saturating_sub(played) as f32 / self.sample_rate as f32)
+    }
+    fn fade_out(&mut self, seconds: f32) {
+        self.fade = -1.0 / seconds.max(1e-3);
+    }
+}
+
+/// Plays music that isn't placed anywhere, on the music bus.  Tracks stream
+/// from disk, crossfade into each other, and follow playlists; games switch
+/// music by naming a cue whenever their state changes.
+pub struct MusicPlayer {
+    output: Option<OutputStreamHandle>,
+    buses: Arc<BusGains>,
+    asset_root: PathBuf,
+    current: Option<Stream>,
+    fading: Vec<Stream>,
+    // The playlist being followed and the index of the current track in it
+    playlist: Option<(Playlist, usize)>,
+    cues: HashMap<String, Playlist>,
+    cue: Option<String>,
+}
+
+impl MusicPlayer {
+    pub(crate) fn new(
+        output: Option<OutputStreamHandle>,
+        buses: Arc<BusGains>,
+        asset_root: &Path,
+    ) -> Self {
+        Self {
+            output,
+            buses,
+            asset_root: asset_root.to_owned(),
+            current: None,
+            fading: vec![],
+            playlist: None,
+            cues: HashMap::new(),
+            cue: None,
+        }
+    }
+    // Start `track`, fading it in over `fade` seconds and the current track out
+    fn start(&mut self, track: &Track, fade: f32, lookahead: f32) -> Result<(), AssetError> {
+        let track = Track {
+            path: self.asset_root.join(&track.path),
+            ..track.clone()
+        };
+        let decoder = track.open()?;
+        let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+        let state = Arc::new(StreamState {
+            gain: AtomicF32::new(0.0),
+            stopped: AtomicBool::new(false),
+            played: AtomicUsize::new(0),
+            total: AtomicUsize::new(usize::MAX),
+            finished: AtomicBool::new(false),
+        });
+        // Decode far enough ahead to see the end coming in time to crossfade
+        let lookahead = lookahead.max(MIN_LOOKAHEAD);
+        let capacity = (lookahead * sample_rate as f32 / CHUNK_FRAMES as f32).ceil() as usize + 2;
+        let (tx, rx) = sync_channel(capacity);
+        let source = StreamSource {
+            chunks: rx,
+            chunk: vec![],
+            pos: 0,
+            channels,
+            sample_rate,
+            gain: 0.0,
+            state: state.clone(),
+            buses: self.buses.clone(),
+        };
+        match &self.output {
+            Some(handle) => {
+                let decoding = state.clone();
+                std::thread::spawn(move || decode(track, decoder, tx, decoding));
+                if let Err(e) = handle.play_raw(source) {
+                    eprintln!("Couldn't play music: {}", e);
+                    state.finished.store(true, Ordering::Relaxed);
+                }
+            }
+            None => state.finished.store(true, Ordering::Relaxed),
+        }
+        self.fade_out(fade);
+        self.current = Some(Stream {
+            state,
+            sample_rate,
+            level: 0.0,
+            fade: 1.0 / fade.max(1e-3),
+        });
+        Ok(())
+    }
+    fn fade_out(&mut self, fade: f32) {
+        if let Some(mut old) = self.current.take() {
+            old.fade_out(fade);
+            self.fading.push(old);
+        }
+    }
+    /// Crossfade to `track` over `fade` seconds, leaving any playlist.
+    pub fn play(&mut self, track: Track, fade: f32) -> Result<(), AssetError> {
+        self.playlist = None;
+        self.cue = None;
+        self.start(&track, fade, 0.0)
+    }
+    /// Crossfade into the first track of `playlist` and carry on through it.
+    pub fn play_playlist(&mut self, playlist: Playlist) {
+        self.cue = None;
+        self.begin(playlist);
+    }
+    fn begin(&mut self, playlist: Playlist) {
+        self.playlist = Some((playlist, 0));
+        self.advance(0);
+    }
+    // Play the playlist's track at `index`, or the first after it that loads
+    fn advance(&mut self, index: usize) {
+        let playlist = match &self.playlist {
+            Some((p, _)) => p.clone(),
+            None => return,
+        };
+        let count = playlist.tracks.len();
+        for i in index..index + count {
+            if i >= count && !playlist.repeat {
+                break;
+            }
+            match self.start(&playlist.tracks[i % count], playlist.fade, playlist.fade) {
+                Ok(()) => {
+                    self.playlist = Some((playlist, i % count));
+                    return;
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        // Nothing (more) to play
+        self.playlist = None;
+        self.fade_out(playlist.fade);
+    }
+    /// Fade the music out over `fade` seconds.
+    pub fn stop(&mut self, fade: f32) {
+        self.playlist = None;
+        self.cue = None;
+        self.fade_out(fade);
+    }
+    /// Name `playlist` so `cue` can switch to it.
+    pub fn add_cue(&mut self, name: &str, playlist: Playlist) {
+        self.cues.insert(name.to_string(), playlist);
+    }
+    /// Switch to the cue called `name`, crossfading with its playlist's fade.
+    /// Does nothing if that cue is already playing, so it's fine to call every
+    /// frame with whatever state the game is in.
+    pub fn cue(&mut self, name: &str) {
+        if self.cue.as_deref() == Some(name) {
+            return;
+        }
+        match self.cues.get(name) {
+            Some(playlist) => {
+                let playlist = playlist.clone();
+                self.begin(playlist);
+                self.cue = Some(name.to_string());
+            }
+            None => eprintln!("No music cue {:?}", name),
+        }
+    }
+    /// The cue last switched to with `cue`.
+    pub fn current_cue(&self) -> Option<&str> {
+        self.cue.as_deref()
+    }
+    pub fn is_playing(&self) -> bool {
+        self.current.is_some()
+    }
+    // Run fades and move through the playlist; `Sound::update` calls this every frame
+    pub(crate) fn update(&mut self, dt: f32) {
+        // Start the next track early enough to crossfade into it
+        let next = match (&self.current, &self.playlist) {
+            (Some(current), Some((playlist, index))) => {
+                let more = index + 1 < playlist.tracks.len() || playlist.repeat;
+                let remaining = current.remaining();
+                let ending = current.state.finished.load(Ordering::Relaxed)
+                    || remaining.map_or(false, |r| r <= playlist.fade);
+                if more && ending {
+                    Some((remaining.unwrap_or(0.0), index + 1))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some((remaining, index)) = next {
+            // The old track fades out over exactly what's left of it
+            self.fade_out(remaining);
+            self.advance(index);
+        }
+        if let Some(current) = &self.current {
+            if current.state.finished.load(Ordering::Relaxed) {
+                self.current = None;
+                self.playlist = None;
+            }
+        }
+        for stream in self.current.iter_mut().chain(self.fading.iter_mut()) {
+            stream.level = (stream.level + stream.fade * dt).max(0.0).min(1.0);
+            stream.state.gain.set(stream.level);
+        }
+        self.fading.retain(|s| {
+            let done = s.level <= 0.0 || s.state.finished.load(Ordering::Relaxed);
+            if done {
+                s.state.stopped.store(true, Ordering::Relaxed);
+            }
+            !done
+        });
+    }
+}
@@ -1,15 +1,33 @@
 use crate::anim::{self, DrawAnimated};
-use crate::assets::{Assets, ModelRef};
+use crate::assets::{Assets, LoadedScene, ModelRef};
 use crate::camera::Camera;
 use crate::model::*;
+use crate::shaders::{DeviceErrors, Shaders};
 use crate::texture;
 use crate::Game;
 use cgmath::SquareMatrix;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::path::Path;
 use wgpu::util::DeviceExt;
 
-pub const BONE_MAX: usize = 128;
+// Keep in sync with shader_bones.vert; 512 bones fill a 16KiB uniform buffer
+pub const BONE_MAX: usize = 512;
+// Also in shader_bones.vert, as 16 vec4s
+pub const MORPH_MAX: usize = 64;
 pub const LIGHT_MAX: usize = 10;
+// How many poses fit in the bone and morph weight buffers at once; frames with
+// more animated instances than this are drawn in several submits
+const POSE_SLOTS: usize = 64;
+// Each pose's slice of those buffers, both multiples of the 256 byte offset alignment
+const BONE_SLOT: wgpu::BufferAddress =
+    (BONE_MAX * std::mem::size_of::<anim::Bone>()) as wgpu::BufferAddress;
+const WEIGHT_SLOT: wgpu::BufferAddress =
+    (MORPH_MAX * std::mem::size_of::<f32>()) as wgpu::BufferAddress;
+
+const STATIC_VS: &str = "shader.vert";
+const BONES_VS: &str = "shader_bones.vert";
+const FS: &str = "shader.frag";
 
 use winit::window::Window;
 pub(crate) struct Render {
@@ -19,7 +37,12 @@ pub(crate) struct Render {
     sc_desc: wgpu::SwapChainDescriptor,
     swap_chain: wgpu::SwapChain,
     pub(crate) size: winit::dpi::PhysicalSize<u32>,
+    // None when there's no shader directory to reload from
+    shaders: Option<Shaders>,
+    errors: DeviceErrors,
+    static_pipeline_layout: wgpu::PipelineLayout,
     static_render_pipeline: wgpu::RenderPipeline,
+    animated_pipeline_layout: wgpu::PipelineLayout,
     animated_render_pipeline: wgpu::RenderPipeline,
     pub(crate) texture_layout: wgpu::BindGroupLayout,
     pub(crate) camera: Camera,
@@ -27,7 +50,12 @@ pub(crate) struct Render {
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
     bone_buffer: wgpu::Buffer,
+    morph_weight_buffer: wgpu::Buffer,
+    bone_bind_group_layout: wgpu::BindGroupLayout,
+    // Bones with no morph targets, for meshes that don't have any
     bone_bind_group: wgpu::BindGroup,
+    // Bones with each mesh's morph targets, made once per uploaded model
+    morph_bind_groups: HashMap<ModelRef, (u64, Vec<Option<wgpu::BindGroup>>)>,
     pub(crate) ambient: f32,
     light_ambient_buffer: wgpu::Buffer,
     lights: Vec<crate::lights::Light>,
@@ -38,7 +66,7 @@ pub(crate) struct Render {
 }
 
 impl Render {
-    pub(crate) async fn new(window: &Window) -> Self {
+    pub(crate) async fn new(window: &Window, shader_dir: Option<&Path>) -> Self {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -142,18 +170,13 @@ impl Render {
             }],
             label: Some("uniform_bind_group"),
         });
-        use crate::geom::*;
-        let lights =
-            vec![crate::lights::Light::point(Pos3::new(0.0, 0.0, 0.0), Vec3::zero()); LIGHT_MAX];
-        let light_uniform_size =
-            (LIGHT_MAX * std::mem::size_of::<crate::lights::Light>()) as wgpu::BufferAddress;
-        let light_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        let lights = vec![crate::lights::Light::off(); LIGHT_MAX];
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Lights buffer"),
-            size: light_uniform_size,
+            contents: bytemuck::cast_slice(&lights),
             usage: wgpu::BufferUsage::UNIFORM
                 | wgpu::BufferUsage::COPY_SRC
                 | wgpu::BufferUsage::COPY_DST,
-            mapped_at_creation: false,
         });
         let light_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -213,160 +236,120 @@ impl Render {
             label: Some("light_bind_group"),
         });
 
-        let bone_uniform_size =
-            (BONE_MAX * std::mem::size_of::<crate::anim::Bone>()) as wgpu::BufferAddress;
         let bone_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Bones buffer"),
-            size: bone_uniform_size,
+            size: BONE_SLOT * POSE_SLOTS as wgpu::BufferAddress,
             usage: wgpu::BufferUsage::UNIFORM
                 | wgpu::BufferUsage::COPY_SRC
                 | wgpu::BufferUsage::COPY_DST,
             mapped_at_creation: false,
         });
+        let morph_weight_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Morph weights buffer"),
+            size: WEIGHT_SLOT * POSE_SLOTS as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
         let bone_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new(
-                            BONE_MAX as u64
-                                * std::mem::size_of::<anim::Bone>() as wgpu::BufferAddress,
-                        ),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            // Each draw picks its pose's slot
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(BONE_SLOT),
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(WEIGHT_SLOT),
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<[u32; 4]>() as wgpu::BufferAddress,
+                            ),
+                        },
+                        count: None,
+                    },
+                ],
                 label: Some("bone_bind_group_layout"),
             });
 
-        let bone_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bone_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: bone_buffer.as_entire_binding(),
-            }],
-            label: Some("bone_bind_group"),
+        // Zero morph targets, and a spare delta so the array isn't empty
+        let no_morphs = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Empty morph buffer"),
+            contents: &[0; std::mem::size_of::<[u32; 4]>() + std::mem::size_of::<MorphDelta>()],
+            usage: wgpu::BufferUsage::STORAGE,
         });
+        let bone_bind_group = create_bone_bind_group(
+            &device,
+            &bone_bind_group_layout,
+            &bone_buffer,
+            &morph_weight_buffer,
+            &no_morphs,
+        );
 
-        let static_vs_module =
-            device.create_shader_module(&wgpu::include_spirv!("shader.vert.spv"));
-        let bones_vs_module =
-            device.create_shader_module(&wgpu::include_spirv!("shader_bones.vert.spv"));
-        let fs_module = device.create_shader_module(&wgpu::include_spirv!("shader.frag.spv"));
+        let errors = DeviceErrors::default();
+        errors.install(&device);
+        let mut shaders = shader_dir.map(Shaders::new);
+        let static_vs_module = shader_module(shaders.as_mut(), &device, &errors, STATIC_VS);
+        let bones_vs_module = shader_module(shaders.as_mut(), &device, &errors, BONES_VS);
+        let fs_module = shader_module(shaders.as_mut(), &device, &errors, FS);
 
         let depth_texture =
             texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
-        let static_render_pipeline = {
-            let static_render_pipeline_layout =
-                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    label: Some("Static Render Pipeline Layout"),
-                    bind_group_layouts: &[
-                        &texture_bind_group_layout,
-                        &uniform_bind_group_layout,
-                        &light_bind_group_layout,
-                    ],
-                    push_constant_ranges: &[],
-                });
-
-            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some("Static Render Pipeline"),
-                layout: Some(&static_render_pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &static_vs_module,
-                    entry_point: "main",
-                    buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &fs_module,
-                    entry_point: "main",
-                    targets: &[wgpu::ColorTargetState {
-                        format: sc_desc.format,
-                        alpha_blend: wgpu::BlendState::REPLACE,
-                        color_blend: wgpu::BlendState::REPLACE,
-                        write_mask: wgpu::ColorWrite::ALL,
-                    }],
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    strip_index_format: None,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: wgpu::CullMode::Back,
-                    // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                },
-                depth_stencil: Some(wgpu::DepthStencilState {
-                    format: texture::Texture::DEPTH_FORMAT,
-                    depth_write_enabled: true,
-                    depth_compare: wgpu::CompareFunction::Less,
-                    stencil: wgpu::StencilState::default(),
-                    bias: wgpu::DepthBiasState::default(),
-                    // Setting this to true requires Features::DEPTH_CLAMPING
-                    clamp_depth: false,
-                }),
-                multisample: wgpu::MultisampleState {
-                    count: 1,
-                    mask: !0,
-                    alpha_to_coverage_enabled: false,
-                },
-            })
-        };
-        let animated_render_pipeline = {
-            let animated_render_pipeline_layout =
-                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    label: Some("Animated Render Pipeline Layout"),
-                    bind_group_layouts: &[
-                        &texture_bind_group_layout,
-                        &uniform_bind_group_layout,
-                        &light_bind_group_layout,
-                        &bone_bind_group_layout,
-                    ],
-                    push_constant_ranges: &[],
-                });
-
-            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some("Animated Render Pipeline"),
-                layout: Some(&animated_render_pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &bones_vs_module,
-                    entry_point: "main",
-                    buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &fs_module,
-                    entry_point: "main",
-                    targets: &[wgpu::ColorTargetState {
-                        format: sc_desc.format,
-                        alpha_blend: wgpu::BlendState::REPLACE,
-                        color_blend: wgpu::BlendState::REPLACE,
-                        write_mask: wgpu::ColorWrite::ALL,
-                    }],
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    strip_index_format: None,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: wgpu::CullMode::Back,
-                    // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                },
-                depth_stencil: Some(wgpu::DepthStencilState {
-                    format: texture::Texture::DEPTH_FORMAT,
-                    depth_write_enabled: true,
-                    depth_compare: wgpu::CompareFunction::Less,
-                    stencil: wgpu::StencilState::default(),
-                    bias: wgpu::DepthBiasState::default(),
-                    // Setting this to true requires Features::DEPTH_CLAMPING
-                    clamp_depth: false,
-                }),
-                multisample: wgpu::MultisampleState {
-                    count: 1,
-                    mask: !0,
-                    alpha_to_coverage_enabled: false,
-                },
-            })
-        };
+        let static_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Static Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &texture_bind_group_layout,
+                    &uniform_bind_group_layout,
+                    &light_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+        let static_render_pipeline = create_render_pipeline(
+            &device,
+            &static_pipeline_layout,
+            &static_vs_module,
+            &fs_module,
+            sc_desc.format,
+            "Static Render Pipeline",
+        );
+        let animated_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Animated Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &texture_bind_group_layout,
+                    &uniform_bind_group_layout,
+                    &light_bind_group_layout,
+                    &bone_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+        let animated_render_pipeline = create_render_pipeline(
+            &device,
+            &animated_pipeline_layout,
+            &bones_vs_module,
+            &fs_module,
+            sc_desc.format,
+            "Animated Render Pipeline",
+        );
 
         Self {
             surface,
@@ -375,7 +358,11 @@ impl Render {
             sc_desc,
             swap_chain,
             size,
+            shaders,
+            errors,
+            static_pipeline_layout,
             static_render_pipeline,
+            animated_pipeline_layout,
             animated_render_pipeline,
             camera,
             uniform_buffer,
@@ -387,13 +374,62 @@ impl Render {
             light_buffer,
             light_bind_group,
             bone_bind_group,
+            morph_bind_groups: HashMap::new(),
+            bone_bind_group_layout,
             bone_buffer,
+            morph_weight_buffer,
             texture_layout: texture_bind_group_layout,
             depth_texture,
             instance_groups: InstanceGroups::new(),
         }
     }
 
+    /// Recompile shaders that changed on disk and rebuild the pipelines using them.
+    /// If compilation or validation fails the old pipeline stays in place.
+    pub(crate) fn reload_shaders(&mut self) {
+        let shaders = match self.shaders.as_mut() {
+            Some(shaders) => shaders,
+            None => return,
+        };
+        let changed = shaders.changed();
+        let uses = |vs: &str| changed.iter().any(|c| c == vs || c == FS);
+        let format = self.sc_desc.format;
+        if uses(STATIC_VS) {
+            match try_render_pipeline(
+                shaders,
+                &self.device,
+                &self.errors,
+                &self.static_pipeline_layout,
+                STATIC_VS,
+                format,
+                "Static Render Pipeline",
+            ) {
+                Ok(p) => {
+                    self.static_render_pipeline = p;
+                    println!("Rebuilt static pipeline");
+                }
+                Err(e) => eprintln!("{}\nKeeping previous static pipeline", e),
+            }
+        }
+        if uses(BONES_VS) {
+            match try_render_pipeline(
+                shaders,
+                &self.device,
+                &self.errors,
+                &self.animated_pipeline_layout,
+                BONES_VS,
+                format,
+                "Animated Render Pipeline",
+            ) {
+                Ok(p) => {
+                    self.animated_render_pipeline = p;
+                    println!("Rebuilt animated pipeline");
+                }
+                Err(e) => eprintln!("{}\nKeeping previous animated pipeline", e),
+            }
+        }
+    }
+
     pub(crate) fn set_ambient(&mut self, amb: f32) {
         self.ambient = amb;
         self.queue
@@ -403,8 +439,11 @@ impl Render {
     pub(crate) fn set_lights(&mut self, ls: Vec<crate::lights::Light>) {
         assert!(ls.len() < LIGHT_MAX);
         self.lights = ls;
+        // The shader goes through every slot, so switch off the ones left over
+        let mut slots = self.lights.clone();
+        slots.resize(LIGHT_MAX, crate::lights::Light::off());
         self.queue
-            .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&self.lights));
+            .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&slots));
     }
 
     pub(crate) fn update_buffers<R, G: Game<StaticData = R>>(
@@ -435,6 +474,56 @@ impl Render {
             texture::Texture::create_depth_texture(&self.device, &self.sc_desc, "depth_texture");
     }
 
+    // Bind each mesh's morph targets next to the bones the first time a model
+    // is drawn after it's uploaded, and forget models that are gone
+    fn update_morph_bind_groups(&mut self, assets: &Assets) {
+        let (device, layout) = (&self.device, &self.bone_bind_group_layout);
+        let (bones, weights) = (&self.bone_buffer, &self.morph_weight_buffer);
+        let morph_bind_groups = &mut self.morph_bind_groups;
+        morph_bind_groups
+            .retain(|mr, (upload, _)| assets.get_model(*mr).map(|m| m.upload_id) == Some(*upload));
+        for mr in self.instance_groups.anim_groups.keys() {
+            let model = match assets.get_model(*mr) {
+                Some(model) if !morph_bind_groups.contains_key(mr) => model,
+                _ => continue,
+            };
+            let groups = model
+                .meshes
+                .iter()
+                .map(|mesh| {
+                    mesh.morph_buffer.as_ref().map(|morphs| {
+                        create_bone_bind_group(device, layout, bones, weights, morphs)
+                    })
+                })
+                .collect();
+            morph_bind_groups.insert(*mr, (model.upload_id, groups));
+        }
+    }
+
+    // Fill one slot of the bone and morph weight buffers per pose, in one write each
+    fn write_poses(&self, assets: &Assets, batch: &[(ModelRef, usize)]) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut bones = Vec::with_capacity(batch.len() * BONE_MAX);
+        let mut weights = vec![0.0_f32; batch.len() * MORPH_MAX];
+        for ((mr, p), slot_weights) in batch.iter().zip(weights.chunks_exact_mut(MORPH_MAX)) {
+            let (_irs, _buf, _cap, group_bones, poses) = &self.instance_groups.anim_groups[mr];
+            bones.extend_from_slice(&group_bones[p * BONE_MAX..(p + 1) * BONE_MAX]);
+            let given = match assets.get_model(*mr) {
+                Some(model) if poses[*p].morphs.is_empty() => &model.morph_weights[..],
+                _ => &poses[*p].morphs[..],
+            };
+            for (w, g) in slot_weights.iter_mut().zip(given.iter()) {
+                *w = *g;
+            }
+        }
+        self.queue
+            .write_buffer(&self.bone_buffer, 0, bytemuck::cast_slice(&bones));
+        self.queue
+            .write_buffer(&self.morph_weight_buffer, 0, bytemuck::cast_slice(&weights));
+    }
+
     pub(crate) fn render<R, G: Game<StaticData = R>>(
         &mut self,
         game: &mut G,
@@ -444,79 +533,269 @@ impl Render {
         self.update_buffers(game, rules, assets);
 
         let frame = self.swap_chain.get_current_frame()?.output;
+        self.update_morph_bind_groups(assets);
 
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
-
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
+        // Every pose to draw, in order; each gets a slot in the bone and weight buffers
+        let poses: Vec<(ModelRef, usize)> = self
+            .instance_groups
+            .anim_groups
+            .iter()
+            .flat_map(|(mr, (_irs, _buf, _cap, _bones, poses))| {
+                (0..poses.len()).map(move |p| (*mr, p))
+            })
+            .collect();
+        // Queue writes land before the next submit, so a slot can only be
+        // reused once the draws that read it have been submitted
+        let mut batches: Vec<&[(ModelRef, usize)]> = poses.chunks(POSE_SLOTS).collect();
+        if batches.is_empty() {
+            batches.push(&[]);
+        }
+        for (b, batch) in batches.iter().enumerate() {
+            self.write_poses(assets, batch);
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Render Encoder"),
+                });
+            // Only the first pass clears and draws the static models
+            let first = b == 0;
+            {
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Render Pass"),
+                    color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &frame.view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: if first {
+                                wgpu::LoadOp::Clear(wgpu::Color {
+                                    r: 0.1,
+                                    g: 0.2,
+                                    b: 0.3,
+                                    a: 1.0,
+                                })
+                            } else {
+                                wgpu::LoadOp::Load
+                            },
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: Some(
+                        wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                            attachment: &self.depth_texture.view,
+                            depth_ops: Some(wgpu::Operations {
+                                load: if first {
+                                    wgpu::LoadOp::Clear(1.0)
+                                } else {
+                                    wgpu::LoadOp::Load
+                                },
+                                store: true,
+                            }),
+                            stencil_ops: None,
+                        },
+                    ),
+                });
 
-            render_pass.set_pipeline(&self.static_render_pipeline);
-            for (mr, (irs, buf, _cap)) in self.instance_groups.static_groups.iter() {
-                render_pass.set_vertex_buffer(1, buf.as_ref().unwrap().slice(..));
-                render_pass.draw_model_instanced(
-                    assets.get_model(*mr).unwrap(),
-                    0..irs.len() as u32,
-                    &self.uniform_bind_group,
-                    &self.light_bind_group,
-                );
-            }
-            render_pass.set_pipeline(&self.animated_render_pipeline);
-            for (mr, (irs, buf, _cap, bones)) in self.instance_groups.anim_groups.iter() {
-                let model = assets.get_model(*mr).unwrap();
-                for (i, (_ir, bones)) in irs.iter().zip(bones.chunks_exact(BONE_MAX)).enumerate() {
-                    let i = i as u64;
-                    render_pass.set_vertex_buffer(
-                        1,
-                        buf.as_ref()
-                            .unwrap()
-                            .slice(i..(i + InstanceRaw::desc().array_stride)),
-                    );
-                    self.queue
-                        .write_buffer(&self.bone_buffer, 0, bytemuck::cast_slice(&bones));
-                    // TODO set up bones for model here and bone bind group?
-                    render_pass.draw_model_skinned(
-                        model,
-                        &self.uniform_bind_group,
-                        &self.light_bind_group,
-                        &self.bone_bind_group,
-                    );
+                if first {
+                    render_pass.set_pipeline(&self.static_render_pipeline);
+                    for (mr, (irs, buf, _cap)) in self.instance_groups.static_groups.iter() {
+                        // Stale handles (to unloaded models) just don't draw
+                        let model = match assets.get_model(*mr) {
+                            Some(model) => model,
+                            None => continue,
+                        };
+                        render_pass.set_vertex_buffer(1, buf.as_ref().unwrap().slice(..));
+                        render_pass.draw_model_instanced(
+                            model,
+                            0..irs.len() as u32,
+                            &self.uniform_bind_group,
+                            &self.light_bind_group,
+                        );
+                    }
+                }
+                render_pass.set_pipeline(&self.animated_render_pipeline);
+                for (slot, (mr, p)) in batch.iter().enumerate() {
+                    let model = match assets.get_model(*mr) {
+                        Some(model) => model,
+                        None => continue,
+                    };
+                    let (_irs, buf, _cap, _bones, poses) = &self.instance_groups.anim_groups[mr];
+                    let mesh_groups = self.morph_bind_groups.get(mr).map(|(_, g)| &g[..]);
+                    let offsets = [
+                        (slot as wgpu::BufferAddress * BONE_SLOT) as wgpu::DynamicOffset,
+                        (slot as wgpu::BufferAddress * WEIGHT_SLOT) as wgpu::DynamicOffset,
+                    ];
+                    render_pass.set_vertex_buffer(1, buf.as_ref().unwrap().slice(..));
+                    for (m, mesh) in model.meshes.iter().enumerate() {
+                        let group = mesh_groups
+                            .and_then(|g| g.get(m))
+                            .and_then(|g| g.as_ref())
+                            .unwrap_or(&self.bone_bind_group);
+                        render_pass.draw_mesh_skinned(
+                            mesh,
+                            &model.materials[mesh.material],
+                            poses[*p].instances.clone(),
+                            &self.uniform_bind_group,
+                            &self.light_bind_group,
+                            group,
+                            &offsets,
+                        );
+                    }
                 }
             }
+            self.queue.submit(std::iter::once(encoder.finish()));
         }
 
-        self.queue.submit(std::iter::once(encoder.finish()));
-
         Ok(())
     }
 }
 
+fn create_bone_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    bones: &wgpu::Buffer,
+    morph_weights: &wgpu::Buffer,
+    morphs: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: bones,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(BONE_SLOT),
+                },
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: morph_weights,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(WEIGHT_SLOT),
+                },
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: morphs.as_entire_binding(),
+            },
+        ],
+        label: Some("bone_bind_group"),
+    })
+}
+
+// The SPIR-V build.rs compiled `name` to, for stages the shader directory doesn't replace
+fn prebuilt(name: &str) -> wgpu::ShaderModuleDescriptor<'static> {
+    match name {
+        STATIC_VS => wgpu::include_spirv!("shader.vert.spv"),
+        BONES_VS => wgpu::include_spirv!("shader_bones.vert.spv"),
+        FS => wgpu::include_spirv!("shader.frag.spv"),
+        _ => panic!("no prebuilt shader {}", name),
+    }
+}
+
+fn shader_module(
+    shaders: Option<&mut Shaders>,
+    device: &wgpu::Device,
+    errors: &DeviceErrors,
+    name: &str,
+) -> wgpu::ShaderModule {
+    match shaders {
+        Some(shaders) if shaders.has(name) => errors
+            .catch(|| shaders.compile(device, name))
+            .unwrap_or_else(|e| {
+                eprintln!("{}\nUsing prebuilt {}", e, name);
+                device.create_shader_module(&prebuilt(name))
+            }),
+        _ => device.create_shader_module(&prebuilt(name)),
+    }
+}
+
+fn try_render_pipeline(
+    shaders: &mut Shaders,
+    device: &wgpu::Device,
+    errors: &DeviceErrors,
+    layout: &wgpu::PipelineLayout,
+    vs: &str,
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> Result<wgpu::RenderPipeline, String> {
+    // Shader validation and interface mismatches only show up as device errors
+    errors.catch(|| {
+        let mut module = |name| {
+            if shaders.has(name) {
+                shaders.compile(device, name)
+            } else {
+                Ok(device.create_shader_module(&prebuilt(name)))
+            }
+        };
+        let vs_module = module(vs)?;
+        let fs_module = module(FS)?;
+        Ok(create_render_pipeline(
+            device, layout, &vs_module, &fs_module, format, label,
+        ))
+    })
+}
+
+fn create_render_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: vs_module,
+            entry_point: "main",
+            buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: fs_module,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                alpha_blend: wgpu::BlendState::REPLACE,
+                color_blend: wgpu::BlendState::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: wgpu::CullMode::Back,
+            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+            polygon_mode: wgpu::PolygonMode::Fill,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+            // Setting this to true requires Features::DEPTH_CLAMPING
+            clamp_depth: false,
+        }),
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+    })
+}
+
+// One set of bones, and maybe morph weights, shared by a run of animated instances
+struct Pose {
+    // Indices into the group's instances
+    instances: Range<u32>,
+    // Empty for the model's default weights
+    morphs: Vec<f32>,
+}
+
 pub struct InstanceGroups {
     static_groups: BTreeMap<ModelRef, (Vec<InstanceRaw>, Option<wgpu::Buffer>, usize)>,
     anim_groups: BTreeMap<
@@ -525,7 +804,9 @@ pub struct InstanceGroups {
             Vec<InstanceRaw>,
             Option<wgpu::Buffer>,
             usize,
+            // BONE_MAX bones for each pose
             Vec<anim::Bone>,
+            Vec<Pose>,
         ),
     >,
 }
@@ -541,12 +822,25 @@ impl InstanceGroups {
         for (_mr, (irs, _buf, _cap)) in self.static_groups.iter_mut() {
             irs.clear();
         }
-        for (_mr, (irs, _buf, _cap, bones)) in self.anim_groups.iter_mut() {
+        for (_mr, (irs, _buf, _cap, bones, poses)) in self.anim_groups.iter_mut() {
             irs.clear();
             bones.clear();
+            poses.clear();
         }
     }
-    fn update_buffers(&mut self, queue: &wgpu::Queue, device: &wgpu::Device, _assets: &Assets) {
+    fn update_buffers(&mut self, queue: &wgpu::Queue, device: &wgpu::Device, assets: &Assets) {
+        // Let go of instance buffers for models that have been unloaded
+        let stale: Vec<ModelRef> = self
+            .static_groups
+            .keys()
+            .chain(self.anim_groups.keys())
+            .filter(|mr| assets.get_model(**mr).is_none())
+            .copied()
+            .collect();
+        for mr in stale {
+            self.static_groups.remove(&mr);
+            self.anim_groups.remove(&mr);
+        }
         for (_mr, (irs, buf, cap)) in self.static_groups.iter_mut() {
             if buf.is_none() || *cap < irs.len() {
                 buf.replace(
@@ -561,7 +855,7 @@ impl InstanceGroups {
                 queue.write_buffer(buf.as_ref().unwrap(), 0, bytemuck::cast_slice(irs));
             }
         }
-        for (_mr, (irs, buf, cap, _bones)) in self.anim_groups.iter_mut() {
+        for (_mr, (irs, buf, cap, _bones, _poses)) in self.anim_groups.iter_mut() {
             if buf.is_none() || *cap < irs.len() {
                 buf.replace(
                     device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -587,6 +881,38 @@ impl InstanceGroups {
             .0
             .extend(ir.into_iter())
     }
+    /// Draw every mesh in a loaded scene at its node's place, with `transform`
+    /// applied to the whole scene.  Skinned meshes are drawn in their bind pose;
+    /// to animate them, draw them with `render_anim` instead.
+    pub fn render_scene(&mut self, scene: &LoadedScene, transform: cgmath::Matrix4<f32>) {
+        let mut stack = scene.roots.clone();
+        while let Some(n) = stack.pop() {
+            let node = &scene.nodes[n];
+            stack.extend(node.children.iter().copied());
+            let mesh = match node.mesh.and_then(|m| scene.meshes.get(m)) {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+            if node.skin.is_some() {
+                // glTF places skinned meshes by their joints rather than their node,
+                // and joints at rest leave vertices where the bind pose put them
+                self.render_anim(
+                    mesh.handle,
+                    InstanceRaw {
+                        model: transform.into(),
+                    },
+                    std::iter::empty(),
+                );
+            } else {
+                self.render(
+                    mesh.handle,
+                    InstanceRaw {
+                        model: (transform * node.world).into(),
+                    },
+                );
+            }
+        }
+    }
     pub fn render_anim(
         &mut self,
         mr: ModelRef,
@@ -595,6 +921,19 @@ impl InstanceGroups {
     ) {
         self.render_anim_batch(mr, std::iter::once(ir), bones);
     }
+    /// Like `render_anim`, setting the model's morph target weights (as from
+    /// `Animator::morph_weights`) instead of using its defaults.
+    pub fn render_anim_morphed(
+        &mut self,
+        mr: ModelRef,
+        ir: InstanceRaw,
+        bones: impl IntoIterator<Item = anim::Bone>,
+        weights: impl IntoIterator<Item = f32>,
+    ) {
+        self.render_anim(mr, ir, bones);
+        let (_irs, _buf, _cap, _bones, poses) = self.anim_groups.get_mut(&mr).unwrap();
+        poses.last_mut().unwrap().morphs = weights.into_iter().take(MORPH_MAX).collect();
+    }
     pub fn render_anim_batch(
         &mut self,
         mr: ModelRef,
@@ -602,8 +941,17 @@ impl InstanceGroups {
         bone: impl IntoIterator<Item = anim::Bone>,
     ) {
         let ref mut groups = self.anim_groups;
-        let (irs, _buf, _cap, bones) = groups.entry(mr).or_insert((vec![], None, 0, vec![]));
+        let (irs, _buf, _cap, bones, poses) =
+            groups
+                .entry(mr)
+                .or_insert((vec![], None, 0, vec![], vec![]));
+        // Every instance in the batch shares this pose
+        let start = irs.len() as u32;
         irs.extend(ir.into_iter());
+        poses.push(Pose {
+            instances: start..irs.len() as u32,
+            morphs: vec![],
+        });
         bones.extend(
             bone.into_iter()
                 .chain(std::iter::repeat_with(anim::Bone::default))
@@ -620,6 +968,18 @@ pub struct InstanceRaw {
 }
 
 impl InstanceRaw {
+    /// The instance for something attached to a joint of this one, given the
+    /// joint's `socket` transform from `Rig::socket` and the attached model's
+    /// `offset` from the joint.
+    pub fn attached(
+        &self,
+        socket: cgmath::Matrix4<f32>,
+        offset: cgmath::Matrix4<f32>,
+    ) -> InstanceRaw {
+        InstanceRaw {
+            model: (cgmath::Matrix4::from(self.model) * socket * offset).into(),
+        }
+    }
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
         wgpu::VertexBufferLayout {
@@ -16,9 +16,13 @@ uniform Uniforms {
 };
 
 struct Light {
-  vec4 pos;
+  vec4 pos;   // w is 0 for directional lights, whose xyz is the way they shine
   vec4 color;
-  // vec4 dir;
+  vec4 dir;   // spot lights only
+  float intensity;
+  float cos_inner;
+  float cos_outer;
+  float _pad;
 };
 
 layout(set=2, binding=0)
@@ -37,20 +41,27 @@ void main() {
   vec3 view_dir = normalize(u_view_position - v_position);
 
   vec3 result = ambient*object_color.xyz;
-  //for (int i = 0; i < 10; i++) {
-    float light_ambient = 0.1;
-    // Point-light specific; change if directional lights, spotlights are used
-    // to branch on e.g. position.w == 0 (directional) or direction.w == 0 (point) or else spot
-    //vec3 light_color = lights[i].color.xyz;
-    //vec3 light_position = lights[i].pos.xyz;
-    //vec3 light_dir = normalize(light_position - v_position);
-    //float diffuse_strength = max(dot(normal, light_dir), 0.0);
-    //vec3 diffuse_color = light_color * diffuse_strength;
-    //vec3 ambient_color = light_color * light_ambient;
-    //vec3 half_dir = normalize(view_dir + light_dir);
-    //float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
-    //vec3 specular_color = specular_strength * light_color;
-    //result += (ambient_color + diffuse_color + specular_color) * object_color.xyz;
-  //}
+  for (int i = 0; i < 10; i++) {
+    if (lights[i].intensity <= 0.0) {
+      continue;
+    }
+    vec3 light_dir;
+    float strength = lights[i].intensity;
+    if (lights[i].pos.w == 0.0) {
+      light_dir = -normalize(lights[i].pos.xyz);
+    } else {
+      vec3 to_light = lights[i].pos.xyz - v_position;
+      float dist2 = max(dot(to_light, to_light), 0.0001);
+      light_dir = to_light * inversesqrt(dist2);
+      // Point lights' cones take in every direction, so this is 1 for them
+      float cos_angle = dot(-light_dir, lights[i].dir.xyz);
+      strength *= smoothstep(lights[i].cos_outer, lights[i].cos_inner, cos_angle) / dist2;
+    }
+    vec3 light_color = lights[i].color.xyz * strength;
+    float diffuse_strength = max(dot(normal, light_dir), 0.0);
+    vec3 half_dir = normalize(view_dir + light_dir);
+    float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
+    result += (diffuse_strength + specular_strength) * light_color * object_color.xyz;
+  }
   f_color = vec4(result, object_color.a);
 }
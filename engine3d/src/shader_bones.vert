@@ -3,7 +3,7 @@
 layout(location=0) in vec3 a_position;
 layout(location=1) in vec2 a_tex_coords;
 layout(location=2) in vec3 a_normal;
-layout(location=3) in uint bone_ids;
+layout(location=3) in uvec4 bone_ids;
 layout(location=4) in vec4 bone_weights;
 
 layout(location=0) out vec2 v_tex_coords;
@@ -22,6 +22,7 @@ uniform Uniforms {
     mat4 u_proj;
 };
 
+// pos.w is the bone's uniform scale
 struct Bone {
     vec4 pos;
     vec4 rot;
@@ -29,16 +30,26 @@ struct Bone {
 
 layout(set=3, binding=0)
 uniform Bones {
-    Bone bones[128];
+    Bone bones[512];
 };
 
-// This shader uses uniforms for bone positions so it won't work with
-// instancing.  In a real application you'd prefer to pass the bone
-// positions as instance data but you can't really, so you need to
-// encode bone positions of each instance into a texture or something
-// and sample it.  Or even better, put the animation data into a
-// texture and use animation state as instance data, then figure out
-// vertex transform from that.
+// 64 weights, four to a vec4
+layout(set=3, binding=1)
+uniform MorphWeights {
+    vec4 morph_weights[16];
+};
+
+// For target t and vertex v, the position delta is at 2*(t*vertex_count+v)
+// and the normal delta right after it
+layout(set=3, binding=2)
+readonly buffer MorphTargets {
+    uvec4 morph_counts; // x: targets, y: vertices
+    vec4 morph_deltas[];
+};
+
+// Bones and morph weights are uniforms, so only instances sharing a pose can
+// be drawn together; the renderer binds each pose's slice of the buffers with
+// dynamic offsets and draws its instances in one call.
 
 
 vec4 quat_mul_s(vec4 q1, float s)
@@ -61,17 +72,30 @@ void main() {
     );
     mat3 normal_matrix = mat3(transpose(inverse(model_matrix)));
 
+    // Morph first, in the mesh's bind pose, then skin the result
+    vec3 position = a_position;
+    vec3 normal = a_normal;
+    for (uint t=0; t < morph_counts.x; t++) {
+      float weight = morph_weights[t / 4][t % 4];
+      if (weight != 0.0) {
+        uint at = 2 * (t * morph_counts.y + uint(gl_VertexIndex));
+        position += morph_deltas[at].xyz * weight;
+        normal += morph_deltas[at + 1].xyz * weight;
+      }
+    }
+
     vec3 new_vertex = vec3(0,0,0);
     vec3 new_normal = vec3(0,0,0);
-    for (int idx=0; idx < 3; idx++) {
-      int index = int(bone_ids >> (8*(3-idx)) & 0x000000FF);
+    for (int idx=0; idx < 4; idx++) {
+      uint index = bone_ids[idx];
       float weight = bone_weights[idx];
-      // weighted rotate-then-translate-by-(rotated)-disp the a_vertex...
+      // weighted scale-rotate-then-translate-by-(rotated)-disp the a_vertex...
       vec4 rot = bones[index].rot;
       vec3 disp = bones[index].pos.xyz;
-      new_vertex += (quat_rot(rot, a_position) + disp)*weight;
+      float scale = bones[index].pos.w;
+      new_vertex += (quat_rot(rot, position * scale) + disp)*weight;
       // TODO inverse transpose instead
-      new_normal += quat_rot(rot, a_normal)*weight;
+      new_normal += quat_rot(rot, normal)*weight;
     }
     v_normal = normal_matrix * new_normal;
     v_tex_coords = a_tex_coords;
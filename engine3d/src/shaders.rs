@@ -0,0 +1,124 @@
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Compiles GLSL from a directory at runtime and reports which files changed,
+/// so the renderer can rebuild the pipelines that use them.
+pub(crate) struct Shaders {
+    dir: PathBuf,
+    compiler: Option<shaderc::Compiler>,
+    _watcher: Option<RecommendedWatcher>,
+    changes: Receiver<DebouncedEvent>,
+}
+
+impl Shaders {
+    pub(crate) fn new(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref().to_owned();
+        let (tx, changes) = channel();
+        let watcher = notify::watcher(tx, Duration::from_millis(250))
+            .and_then(|mut w| w.watch(&dir, RecursiveMode::NonRecursive).map(|_| w))
+            .map_err(|e| eprintln!("Not watching {:?} for shader changes: {}", dir, e))
+            .ok();
+        let compiler = shaderc::Compiler::new();
+        if compiler.is_none() {
+            eprintln!("Unable to create shader compiler, using prebuilt shaders");
+        }
+        Self {
+            dir,
+            compiler,
+            _watcher: watcher,
+            changes,
+        }
+    }
+
+    /// Whether the shader directory has its own copy of `name`.
+    pub(crate) fn has(&self, name: &str) -> bool {
+        self.dir.join(name).is_file()
+    }
+
+    /// Compile `name` (e.g. "shader.frag") from the shader directory.  The error
+    /// string carries shaderc's "file:line: error" messages.
+    pub(crate) fn compile(
+        &mut self,
+        device: &wgpu::Device,
+        name: &str,
+    ) -> Result<wgpu::ShaderModule, String> {
+        let path = self.dir.join(name);
+        let kind = match path.extension().and_then(|e| e.to_str()) {
+            Some("vert") => shaderc::ShaderKind::Vertex,
+            Some("frag") => shaderc::ShaderKind::Fragment,
+            Some("comp") => shaderc::ShaderKind::Compute,
+            _ => return Err(format!("Unsupported shader: {}", path.display())),
+        };
+        let compiler = self
+            .compiler
+            .as_mut()
+            .ok_or_else(|| "No shader compiler available".to_string())?;
+        let src =
+            std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let compiled = compiler
+            .compile_into_spirv(&src, kind, &path.to_string_lossy(), "main", None)
+            .map_err(|e| e.to_string())?;
+        if compiled.get_num_warnings() > 0 {
+            eprintln!("{}", compiled.get_warning_messages());
+        }
+        Ok(device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some(name),
+            source: wgpu::util::make_spirv(compiled.as_binary_u8()),
+            flags: wgpu::ShaderFlags::VALIDATION,
+        }))
+    }
+
+    /// File names of shaders that changed since the last call.
+    pub(crate) fn changed(&mut self) -> Vec<String> {
+        let mut changed = vec![];
+        while let Ok(ev) = self.changes.try_recv() {
+            match ev {
+                DebouncedEvent::Create(p)
+                | DebouncedEvent::Write(p)
+                | DebouncedEvent::Rename(_, p) => {
+                    if let Some(name) = p.file_name().and_then(|n| n.to_str()) {
+                        changed.push(name.to_string());
+                    }
+                }
+                DebouncedEvent::Error(e, p) => eprintln!("Watch error {:?}: {}", p, e),
+                _ => {}
+            }
+        }
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+}
+
+/// wgpu 0.7 has no error scopes and panics on any validation error by default.
+/// This handler keeps that behaviour, except inside `catch`, where errors are
+/// collected and returned so a bad shader edit can't take the game down.
+#[derive(Clone, Default)]
+pub(crate) struct DeviceErrors(Arc<Mutex<Option<Vec<String>>>>);
+
+impl DeviceErrors {
+    pub(crate) fn install(&self, device: &wgpu::Device) {
+        let errors = self.clone();
+        device.on_uncaptured_error(move |e| match errors.0.lock().unwrap().as_mut() {
+            Some(caught) => caught.push(e.to_string()),
+            None => {
+                eprintln!("wgpu error: {}\n", e);
+                panic!("Handling wgpu errors as fatal by default");
+            }
+        });
+    }
+
+    /// Run `f`, failing if it fails or if the device reported any errors meanwhile.
+    pub(crate) fn catch<T>(&self, f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+        *self.0.lock().unwrap() = Some(vec![]);
+        let result = f();
+        let caught = self.0.lock().unwrap().take().unwrap_or_default();
+        match result {
+            Ok(_) if !caught.is_empty() => Err(caught.join("\n")),
+            result => result,
+        }
+    }
+}
@@ -1,77 +1,805 @@
-use rodio::{SpatialSink};
+use crate::assets::{AssetError, AssetErrorKind};
+use crate::camera::Camera;
+use crate::geom::*;
+use crate::mixer::{AtomicF32, Bus, BusGains, Mixer};
+use crate::music::MusicPlayer;
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Source};
+use std::collections::HashMap;
 use std::io::BufReader;
-use std::path::{Path};
-use std::thread;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-// const TESTSOUND: str = "content/music.ogg";
 
+// How many frames a voice plays between looks at its controls
+const CONTROL_INTERVAL: usize = 64;
+
+/// A sound decoded into memory by `Sound::load`, ready to play any number of times at once.
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct SoundRef(u32);
+
+/// One playing instance of a sound, for controlling it while it plays.  Stays
+/// valid after the voice ends; controlling a finished voice does nothing.
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct Voice(u64);
+
+// Interleaved samples for a whole sound
+struct ClipData {
+    samples: Vec<f32>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl ClipData {
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+    // Channel `ch` of frame `frame`, linearly interpolated between whole frames.
+    // Past the last frame a looping clip heads back to the first; others hold it.
+    fn sample(&self, frame: f64, ch: usize, looping: bool) -> f32 {
+        let channels = self.channels as usize;
+        let f0 = frame.floor() as usize;
+        let f1 = if f0 + 1 < self.frames() {
+            f0 + 1
+        } else if looping {
+            0
+        } else {
+            f0
+        };
+        let s = (frame - frame.floor()) as f32;
+        let (a, b) = (
+            self.samples[f0 * channels + ch],
+            self.samples[f1 * channels + ch],
+        );
+        a + (b - a) * s
+    }
+}
+
+// Speed of sound in world units (metres) per second, for Doppler shifts
+const SPEED_OF_SOUND: f32 = 343.0;
+
+/// Where placed sounds are heard from.  `run` moves it along with the camera
+/// every frame until the game places it with `Sound::set_listener`.
+#[derive(Clone, Copy, Debug)]
+pub struct Listener {
+    pub position: Pos3,
+    /// Which way the listener faces; needn't be normalized
+    pub forward: Vec3,
+    pub up: Vec3,
+    /// In units per second, for Doppler shifts
+    pub velocity: Vec3,
+}
+
+impl Listener {
+    /// A listener at the camera's eye, looking where it looks.
+    pub fn from_camera(camera: &Camera, velocity: Vec3) -> Self {
+        Self {
+            position: camera.eye,
+            forward: camera.target - camera.eye,
+            up: camera.up,
+            velocity,
+        }
+    }
+    // `pos` in the listener's frame: x to its right, y up, z ahead
+    fn local(&self, pos: Pos3) -> Vec3 {
+        let forward = if self.forward.magnitude2() > 1e-12 {
+            self.forward.normalize()
+        } else {
+            Vec3::unit_z()
+        };
+        let mut right = forward.cross(self.up);
+        if right.magnitude2() < 1e-12 {
+            // Looking straight along `up` leaves no right; take one across the view instead
+            let across = if forward.x.abs() < 0.9 {
+                Vec3::unit_x()
+            } else {
+                Vec3::unit_z()
+            };
+            right = across - forward * across.dot(forward);
+        }
+        let right = right.normalize();
+        let up = right.cross(forward);
+        let rel = pos - self.position;
+        Vec3::new(rel.dot(right), rel.dot(up), rel.dot(forward))
+    }
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Self {
+            position: Pos3::new(0.0, 0.0, 0.0),
+            forward: Vec3::unit_z(),
+            up: Vec3::unit_y(),
+            velocity: Vec3::zero(),
+        }
+    }
+}
+
+/// How a placed sound gets quieter with distance from the listener.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Attenuation {
+    /// Just as loud everywhere
+    None,
+    /// Full volume out to `min_distance`, then falling off like `1/distance`;
+    /// higher `rolloff` falls off faster
+    Inverse { min_distance: f32, rolloff: f32 },
+    /// Fades evenly from full volume at `min_distance` to silence at `max_distance`
+    Linear {
+        min_distance: f32,
+        max_distance: f32,
+    },
+    /// Full volume out to `min_distance`, then `(distance / min_distance)^-rolloff`
+    Exponential { min_distance: f32, rolloff: f32 },
+}
+
+impl Attenuation {
+    pub fn gain(&self, distance: f32) -> f32 {
+        match *self {
+            Attenuation::None => 1.0,
+            Attenuation::Inverse {
+                min_distance,
+                rolloff,
+            } => {
+                let d = distance.max(min_distance);
+                min_distance / (min_distance + rolloff * (d - min_distance)).max(1e-4)
+            }
+            Attenuation::Linear {
+                min_distance,
+                max_distance,
+            } => {
+                if distance <= min_distance {
+                    1.0
+                } else if distance >= max_distance {
+                    0.0
+                } else {
+                    1.0 - (distance - min_distance) / (max_distance - min_distance)
+                }
+            }
+            Attenuation::Exponential {
+                min_distance,
+                rolloff,
+            } => (distance.max(min_distance) / min_distance.max(1e-4)).powf(-rolloff),
+        }
+    }
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Attenuation::Inverse {
+            min_distance: 1.0,
+            rolloff: 1.0,
+        }
+    }
+}
+
+// An emitter travelling from one world position to another over some seconds
+#[derive(Clone, Copy, Debug)]
+struct EmitterPath {
+    from: Pos3,
+    to: Pos3,
+    duration: f32,
+}
+
+impl EmitterPath {
+    fn at(&self, t: f32) -> Pos3 {
+        if self.duration <= 0.0 {
+            self.to
+        } else {
+            self.from + (self.to - self.from) * (t / self.duration).min(1.0)
+        }
+    }
+    // None once it has arrived
+    fn velocity(&self, t: f32) -> Option<Vec3> {
+        if t < self.duration {
+            Some((self.to - self.from) / self.duration)
+        } else {
+            None
+        }
+    }
+}
+
+// Where a placed voice is and how it carries
+#[derive(Clone, Copy, Debug)]
+struct Emitter {
+    // Goes up with each new path so the audio thread knows to restart its clock
+    count: u64,
+    path: EmitterPath,
+    // Used once the emitter isn't following a path
+    velocity: Vec3,
+    attenuation: Attenuation,
+}
+
+// Shared by every voice: who's listening, and how strong Doppler shifts are
+#[derive(Clone, Copy, Debug)]
+struct Hearing {
+    listener: Listener,
+    doppler: f32,
+}
+
+// What the game can change about a voice while it plays; the audio thread
+// picks changes up every CONTROL_INTERVAL frames
+#[derive(Debug)]
+struct Controls {
+    volume: AtomicF32,
+    pitch: AtomicF32,
+    // Index into Bus::ALL
+    bus: AtomicUsize,
+    paused: AtomicBool,
+    looping: AtomicBool,
+    stopped: AtomicBool,
+    // Set by the audio thread once the voice has played out
+    finished: AtomicBool,
+    // None for sounds that aren't placed anywhere
+    emitter: Mutex<Option<Emitter>>,
+}
+
+// Plays a clip for one voice, always in stereo
+struct VoiceSource {
+    clip: Arc<ClipData>,
+    controls: Arc<Controls>,
+    // Where in the clip playback is, in frames; fractional once pitch changes
+    cursor: f64,
+    frame: [f32; 2],
+    channel: usize,
+    // Copies of the controls, refreshed every CONTROL_INTERVAL frames
+    until_refresh: usize,
+    volume: f32,
+    pitch: f32,
+    paused: bool,
+    looping: bool,
+    gains: [f32; 2],
+    // The Doppler shift, on top of `pitch`
+    shift: f32,
+    emitter: Option<Emitter>,
+    path_time: f32,
+    hearing: Arc<Mutex<Hearing>>,
+    listener: Hearing,
+    buses: Arc<BusGains>,
+}
+
+impl VoiceSource {
+    fn new(
+        clip: Arc<ClipData>,
+        controls: Arc<Controls>,
+        hearing: Arc<Mutex<Hearing>>,
+        buses: Arc<BusGains>,
+    ) -> Self {
+        let listener = *hearing.lock().unwrap();
+        Self {
+            clip,
+            controls,
+            cursor: 0.0,
+            frame: [0.0; 2],
+            channel: 0,
+            until_refresh: 0,
+            volume: 1.0,
+            pitch: 1.0,
+            paused: false,
+            looping: false,
+            gains: [1.0; 2],
+            shift: 1.0,
+            emitter: None,
+            path_time: 0.0,
+            hearing,
+            listener,
+            buses,
+        }
+    }
+    fn refresh(&mut self) {
+        let c = &self.controls;
+        self.volume = c.volume.get() * self.buses.get(Bus::ALL[c.bus.load(Ordering::Relaxed)]);
+        self.pitch = c.pitch.get().max(0.0);
+        self.paused = c.paused.load(Ordering::Relaxed);
+        self.looping = c.looping.load(Ordering::Relaxed);
+        // Never wait on the game; an update missed now gets picked up next time
+        if let Ok(emitter) = c.emitter.try_lock() {
+            if emitter.map(|e| e.count) != self.emitter.map(|e| e.count) {
+                self.path_time = 0.0;
+            }
+            self.emitter = *emitter;
+        }
+        if let Ok(hearing) = self.hearing.try_lock() {
+            self.listener = *hearing;
+        }
+        let (gains, shift) = match self.emitter {
+            Some(emitter) => place(&emitter, self.path_time, &self.listener),
+            None => ([1.0, 1.0], 1.0),
+        };
+        self.gains = gains;
+        self.shift = shift;
+        self.until_refresh = CONTROL_INTERVAL;
+    }
+    // Work out the next stereo frame, or false if the voice is over
+    fn advance(&mut self) -> bool {
+        if self.controls.stopped.load(Ordering::Relaxed) {
+            return false;
+        }
+        if self.until_refresh == 0 {
+            self.refresh();
+        }
+        self.until_refresh -= 1;
+        self.path_time += 1.0 / self.clip.sample_rate as f32;
+        if self.paused {
+            self.frame = [0.0; 2];
+            return true;
+        }
+        let frames = self.clip.frames() as f64;
+        if self.cursor >= frames {
+            if !self.looping || frames == 0.0 {
+                return false;
+            }
+            self.cursor %= frames;
+        }
+        let (l, r) = if self.clip.channels == 1 {
+            let s = self.clip.sample(self.cursor, 0, self.looping);
+            (s, s)
+        } else {
+            (
+                self.clip.sample(self.cursor, 0, self.looping),
+                self.clip.sample(self.cursor, 1, self.looping),
+            )
+        };
+        // Placed sounds come from a point, so they're mixed down before panning
+        let (l, r) = if self.emitter.is_some() {
+            let mono = (l + r) / 2.0;
+            (mono, mono)
+        } else {
+            (l, r)
+        };
+        self.frame = [
+            l * self.gains[0] * self.volume,
+            r * self.gains[1] * self.volume,
+        ];
+        self.cursor += (self.pitch * self.shift) as f64;
+        true
+    }
+}
+
+impl Iterator for VoiceSource {
+    type Item = f32;
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 && !self.advance() {
+            self.controls.finished.store(true, Ordering::Relaxed);
+            return None;
+        }
+        let s = self.frame[self.channel];
+        self.channel = 1 - self.channel;
+        Some(s)
+    }
+}
+
+impl Source for VoiceSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+    fn channels(&self) -> u16 {
+        2
+    }
+    fn sample_rate(&self) -> u32 {
+        self.clip.sample_rate
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Left and right gains and the Doppler shift for an emitter `t` seconds along its path
+fn place(emitter: &Emitter, t: f32, hearing: &Hearing) -> ([f32; 2], f32) {
+    let listener = &hearing.listener;
+    let pos = emitter.path.at(t);
+    let local = listener.local(pos);
+    let dist = local.magnitude();
+    let gain = emitter.attenuation.gain(dist);
+    if dist < 1e-4 {
+        return ([gain, gain], 1.0);
+    }
+    // Equal-power panning, boosted so a sound straight ahead plays at full volume
+    let side = (local.x / dist).max(-1.0).min(1.0);
+    let angle = (1.0 + side) * PI / 4.0;
+    let gains = [
+        (angle.cos() * std::f32::consts::SQRT_2).min(1.0) * gain,
+        (angle.sin() * std::f32::consts::SQRT_2).min(1.0) * gain,
+    ];
+    if hearing.doppler <= 0.0 {
+        return (gains, 1.0);
+    }
+    // Speeds along the line from listener to emitter: the emitter's is positive
+    // moving away from the listener, the listener's positive moving towards the emitter
+    let away = (pos - listener.position) / dist;
+    let emitter_vel = emitter.path.velocity(t).unwrap_or(emitter.velocity);
+    let c = SPEED_OF_SOUND;
+    // Keep it clear of the sound barrier, where the shift blows up
+    let limit = c * 0.9;
+    let source = (emitter_vel.dot(away) * hearing.doppler)
+        .max(-limit)
+        .min(limit);
+    let receiver = (listener.velocity.dot(away) * hearing.doppler)
+        .max(-limit)
+        .min(limit);
+    (gains, (c + receiver) / (c + source))
+}
+
+/// Plays sounds without ever waiting for them.  Load sounds once with `load`,
+/// then start as many overlapping voices of them as you like and control each
+/// while it plays; everything that changes during playback is worked out on
+/// the audio thread.
 pub struct Sound {
-    pub sink: SpatialSink,
+    // None if there's no audio device; everything still works, silently
+    output: Option<(OutputStream, OutputStreamHandle)>,
+    asset_root: PathBuf,
+    clips: Vec<Arc<ClipData>>,
+    paths: HashMap<PathBuf, SoundRef>,
+    voices: HashMap<Voice, Arc<Controls>>,
+    next_voice: u64,
+    hearing: Arc<Mutex<Hearing>>,
+    // Whether the listener has been placed yet, so its first move isn't taken as speed
+    listener_placed: bool,
+    // Whether `run` keeps the listener on the camera
+    follows_camera: bool,
+    attenuation: Attenuation,
+    mixer: Mixer,
+    music: MusicPlayer,
 }
 
 impl Sound {
-    pub fn add_sound<P: AsRef<Path>>(&self, path: P) {
-        // println!("found file? {}", std::fs::File::open(&path).is_ok());
-        let file = std::fs::File::open(path).unwrap();
-        let source = rodio::Decoder::new(BufReader::new(file)).unwrap();
-        self.sink.append(source);
-    }
-    pub fn play_left_to_right(&self, xdisp: f32) {
-        for i in 1..(xdisp as i32 * 25) {
-            thread::sleep(Duration::from_millis(5));
-            self.sink
-                .set_emitter_position([(i - 500) as f32 / 50.0, 0.0, 0.0]);
-        }
-    }
-    pub fn play_bottom_to_top(&self, zdisp: f32) {
-        for i in 1..-(zdisp as i32 * 25) {
-            thread::sleep(Duration::from_millis(5));
-            self.sink
-                .set_emitter_position([0.0, 0.0, (i - 500) as f32 / 50.0]);
-        }
-    }
-    pub fn play_top_to_bottom(&self, zdisp: f32) {
-        for i in 1..(zdisp as i32 * 20) {
-            thread::sleep(Duration::from_millis(5));
-            self.sink
-                .set_emitter_position([0.0, 0.0, -(i - 500) as f32 / 50.0]);
-        }
-    }
-    pub fn play_right_to_left(&self, xdisp: f32) {
-        for i in 1..-(xdisp as i32 * 25) {
-            thread::sleep(Duration::from_millis(5));
-            self.sink
-                .set_emitter_position([-(i - 500) as f32 / 50.0, 0.0, 0.0]);
-        }
-    }
-    // pub fn playleft_to_right(&self) {
-    //     // self.sink.set_volume(10.0);
-    //     for i in 1..1001 {
-    //         // println!("left i: {} ", i);
-    //         thread::sleep(Duration::from_millis(5));
-    //         self.sink.set_emitter_position([(i - 500) as f32 / 50.0, 0.0, 0.0]);
-    //     }
-    //     for i in 1..1001 {
-    //         // println!("right i: {} ", i);
-    //         thread::sleep(Duration::from_millis(1));
-    //         self.sink.set_emitter_position([-(i - 500) as f32 / 50.0, 0.0, 0.0]);
-    //     }
-    //     // println!("done playing; sink is empty? {} ", self.sink.empty());
-    //     // std::thread::sleep(std::time::Duration::from_secs(1));
-    // }
-}
-
-// println!("outputs: {}", rodio::OutputStream::try_default().is_ok());
-// let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
-// let sink = rodio::SpatialSink::try_new(
-//     &handle,
-//     [-10.0, 0.0, 0.0],
-//     [1.0, 0.0, 0.0],
-//     [-1.0, 0.0, 0.0],
-// )
-// .unwrap();
-
-// // print!("Audio is being read: {}", std::fs::File::open("content/music.mp3").is_ok());
-// let file = std::fs::File::open("content/music.ogg").unwrap();
-// let source = rodio::Decoder::new(BufReader::new(file)).unwrap();
-// sink.append(source);
+    /// Sounds and music load from under `asset_root`.
+    pub fn new(asset_root: impl AsRef<Path>) -> Self {
+        let asset_root = asset_root.as_ref().to_owned();
+        let output = OutputStream::try_default()
+            .map_err(|e| eprintln!("No audio output, sound is off: {}", e))
+            .ok();
+        let mixer = Mixer::new();
+        let music = MusicPlayer::new(
+            output.as_ref().map(|(_, h)| h.clone()),
+            mixer.gains(),
+            &asset_root,
+        );
+        Self {
+            output,
+            asset_root,
+            clips: vec![],
+            paths: HashMap::new(),
+            voices: HashMap::new(),
+            next_voice: 0,
+            hearing: Arc::new(Mutex::new(Hearing {
+                listener: Listener::default(),
+                doppler: 0.0,
+            })),
+            listener_placed: false,
+            follows_camera: true,
+            attenuation: Attenuation::default(),
+            mixer,
+            music,
+        }
+    }
+    /// Decode a sound file, relative to the asset root, into memory.  Loading
+    /// the same path again hands back the same sound.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<SoundRef, AssetError> {
+        let path = path.as_ref();
+        if let Some(sound) = self.paths.get(path) {
+            return Ok(*sound);
+        }
+        let full = self.asset_root.join(path);
+        let file = std::fs::File::open(&full)
+            .map_err(|e| AssetError::new(&full, AssetErrorKind::Io(e)))?;
+        let decoder = Decoder::new(BufReader::new(file))
+            .map_err(|e| AssetError::new(&full, AssetErrorKind::Audio(e)))?;
+        let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+        let samples: Vec<f32> = decoder.convert_samples().collect();
+        if channels == 0 || samples.is_empty() {
+            return Err(AssetError::new(
+                &full,
+                AssetErrorKind::Invalid("no audio in file".into()),
+            ));
+        }
+        let sound = SoundRef(self.clips.len() as u32);
+        self.clips.push(Arc::new(ClipData {
+            samples,
+            channels,
+            sample_rate,
+        }));
+        self.paths.insert(path.to_owned(), sound);
+        Ok(sound)
+    }
+    /// How long `sound` plays for at normal pitch.
+    pub fn duration(&self, sound: SoundRef) -> Duration {
+        let clip = &self.clips[sound.0 as usize];
+        Duration::from_secs_f64(clip.frames() as f64 / clip.sample_rate as f64)
+    }
+    fn start(&mut self, sound: SoundRef, bus: Bus, emitter: Option<EmitterPath>) -> Voice {
+        let voice = Voice(self.next_voice);
+        self.next_voice += 1;
+        let controls = Arc::new(Controls {
+            volume: AtomicF32::new(1.0),
+            pitch: AtomicF32::new(1.0),
+            bus: AtomicUsize::new(bus.index()),
+            paused: AtomicBool::new(false),
+            looping: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            emitter: Mutex::new(emitter.map(|path| Emitter {
+                count: 0,
+                path,
+                velocity: Vec3::zero(),
+                attenuation: self.attenuation,
+            })),
+        });
+        let source = VoiceSource::new(
+            self.clips[sound.0 as usize].clone(),
+            controls.clone(),
+            self.hearing.clone(),
+            self.mixer.gains(),
+        );
+        // The audio thread may finish a short clip before play_raw returns, so
+        // only mark the voice finished here if it never started
+        match &self.output {
+            Some((_stream, handle)) => {
+                if let Err(e) = handle.play_raw(source) {
+                    eprintln!("Couldn't play sound: {}", e);
+                    controls.finished.store(true, Ordering::Relaxed);
+                }
+            }
+            None => controls.finished.store(true, Ordering::Relaxed),
+        }
+        self.voices.insert(voice, controls);
+        voice
+    }
+    /// Play `sound` as is, not placed anywhere, on the sfx bus.
+    pub fn play(&mut self, sound: SoundRef) -> Voice {
+        self.play_on(Bus::Sfx, sound)
+    }
+    /// Play `sound` as is on `bus`, e.g. `Bus::Ui` for menu clicks.
+    pub fn play_on(&mut self, bus: Bus, sound: SoundRef) -> Voice {
+        self.start(sound, bus, None)
+    }
+    /// Play `sound` from `pos` in the world.
+    pub fn play_at(&mut self, sound: SoundRef, pos: [f32; 3]) -> Voice {
+        self.play_moving(sound, pos, pos, Duration::from_secs(0))
+    }
+    /// Play `sound` with its emitter travelling in a straight line from `from`
+    /// to `to` over `duration`, then staying put.
+    pub fn play_moving(
+        &mut self,
+        sound: SoundRef,
+        from: [f32; 3],
+        to: [f32; 3],
+        duration: Duration,
+    ) -> Voice {
+        self.start(
+            sound,
+            Bus::Sfx,
+            Some(EmitterPath {
+                from: from.into(),
+                to: to.into(),
+                duration: duration.as_secs_f32(),
+            }),
+        )
+    }
+    fn controls(&self, voice: Voice) -> Option<&Controls> {
+        self.voices.get(&voice).map(|c| c.as_ref())
+    }
+    pub fn stop(&mut self, voice: Voice) {
+        if let Some(c) = self.voices.remove(&voice) {
+            c.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+    pub fn pause(&self, voice: Voice) {
+        if let Some(c) = self.controls(voice) {
+            c.paused.store(true, Ordering::Relaxed);
+        }
+    }
+    pub fn resume(&self, voice: Voice) {
+        if let Some(c) = self.controls(voice) {
+            c.paused.store(false, Ordering::Relaxed);
+        }
+    }
+    /// 1.0 plays at the volume the sound was recorded at.
+    pub fn set_volume(&self, voice: Voice, volume: f32) {
+        if let Some(c) = self.controls(voice) {
+            c.volume.set(volume.max(0.0));
+        }
+    }
+    /// Playback rate, which also shifts pitch; 2.0 is an octave up and twice as fast.
+    pub fn set_pitch(&self, voice: Voice, pitch: f32) {
+        if let Some(c) = self.controls(voice) {
+            c.pitch.set(pitch.max(0.0));
+        }
+    }
+    /// Move a voice to another bus, e.g. `Bus::Voice` for placed dialogue.
+    pub fn set_bus(&self, voice: Voice, bus: Bus) {
+        if let Some(c) = self.controls(voice) {
+            c.bus.store(bus.index(), Ordering::Relaxed);
+        }
+    }
+    /// Whether the voice starts over when it reaches the end.
+    pub fn set_looping(&self, voice: Voice, looping: bool) {
+        if let Some(c) = self.controls(voice) {
+            c.looping.store(looping, Ordering::Relaxed);
+        }
+    }
+    /// Put a voice's emitter at `pos` in the world from now on.  Call this
+    /// every frame to keep a sound on a moving entity, along with
+    /// `set_velocity` if it should Doppler shift.
+    pub fn set_position(&self, voice: Voice, pos: [f32; 3]) {
+        self.move_to(voice, pos, pos, Duration::from_secs(0));
+    }
+    /// Send a voice's emitter from `from` to `to` over `duration`.
+    pub fn move_to(&self, voice: Voice, from: [f32; 3], to: [f32; 3], duration: Duration) {
+        let path = EmitterPath {
+            from: from.into(),
+            to: to.into(),
+            duration: duration.as_secs_f32(),
+        };
+        let attenuation = self.attenuation;
+        self.with_emitter(voice, |emitter| match emitter {
+            Some(e) => {
+                e.count += 1;
+                e.path = path;
+            }
+            None => {
+                *emitter = Some(Emitter {
+                    count: 0,
+                    path,
+                    velocity: Vec3::zero(),
+                    attenuation,
+                })
+            }
+        });
+    }
+    /// How fast a placed voice's emitter is moving, in units per second, when
+    /// it isn't following a path from `move_to`.  Only matters for Doppler shifts.
+    pub fn set_velocity(&self, voice: Voice, velocity: [f32; 3]) {
+        self.with_emitter(voice, |emitter| {
+            if let Some(e) = emitter {
+                e.velocity = velocity.into();
+            }
+        });
+    }
+    /// How a placed voice fades with distance.
+    pub fn set_attenuation(&self, voice: Voice, attenuation: Attenuation) {
+        self.with_emitter(voice, |emitter| {
+            if let Some(e) = emitter {
+                e.attenuation = attenuation;
+            }
+        });
+    }
+    /// How voices placed from now on fade with distance.
+    pub fn set_default_attenuation(&mut self, attenuation: Attenuation) {
+        self.attenuation = attenuation;
+    }
+    fn with_emitter(&self, voice: Voice, f: impl FnOnce(&mut Option<Emitter>)) {
+        if let Some(c) = self.controls(voice) {
+            f(&mut c.emitter.lock().unwrap());
+        }
+    }
+    /// Scale Doppler shifts by `factor`; 0.0, the default, turns them off and
+    /// 1.0 is true to life.
+    pub fn set_doppler(&self, factor: f32) {
+        self.hearing.lock().unwrap().doppler = factor.max(0.0);
+    }
+    pub fn listener(&self) -> Listener {
+        self.hearing.lock().unwrap().listener
+    }
+    /// Place the listener.  `run` stops moving it with the camera from then on;
+    /// call `set_follows_camera(true)` to hand it back.
+    pub fn set_listener(&mut self, listener: Listener) {
+        self.place_listener(listener);
+        self.follows_camera = false;
+    }
+    fn place_listener(&mut self, listener: Listener) {
+        self.hearing.lock().unwrap().listener = listener;
+        self.listener_placed = true;
+    }
+    /// Whether `run` moves the listener along with the camera every frame.
+    pub fn follows_camera(&self) -> bool {
+        self.follows_camera
+    }
+    pub fn set_follows_camera(&mut self, follow: bool) {
+        self.follows_camera = follow;
+    }
+    /// Move the listener to `camera`, which has moved over the last `dt` seconds.
+    pub fn follow_camera(&mut self, camera: &Camera, dt: f32) {
+        let velocity = if self.listener_placed && dt > 0.0 {
+            (camera.eye - self.listener().position) / dt
+        } else {
+            Vec3::zero()
+        };
+        self.place_listener(Listener::from_camera(camera, velocity));
+    }
+    /// False once the voice has been stopped or has played to the end.
+    pub fn is_playing(&self, voice: Voice) -> bool {
+        self.controls(voice)
+            .map_or(false, |c| !c.finished.load(Ordering::Relaxed))
+    }
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+    pub fn mixer_mut(&mut self) -> &mut Mixer {
+        &mut self.mixer
+    }
+    pub fn music(&self) -> &MusicPlayer {
+        &self.music
+    }
+    pub fn music_mut(&mut self) -> &mut MusicPlayer {
+        &mut self.music
+    }
+    /// Forget voices that have finished, and run ducking and music over `dt`
+    /// seconds; `run` calls this every frame.
+    pub fn update(&mut self, dt: f32) {
+        self.voices
+            .retain(|_, c| !c.finished.load(Ordering::Relaxed));
+        let voices = &self.voices;
+        self.mixer.update(dt, |bus| {
+            voices.values().any(|c| {
+                c.bus.load(Ordering::Relaxed) == bus.index() && !c.paused.load(Ordering::Relaxed)
+            })
+        });
+        self.music.update(dt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A still emitter 10 units straight ahead of a listener at the origin
+    fn ahead(listener_velocity: Vec3, emitter_velocity: Vec3) -> f32 {
+        let at = Pos3::new(0.0, 0.0, 10.0);
+        let emitter = Emitter {
+            count: 0,
+            path: EmitterPath {
+                from: at,
+                to: at,
+                duration: 0.0,
+            },
+            velocity: emitter_velocity,
+            attenuation: Attenuation::default(),
+        };
+        let hearing = Hearing {
+            listener: Listener {
+                velocity: listener_velocity,
+                ..Listener::default()
+            },
+            doppler: 1.0,
+        };
+        place(&emitter, 0.0, &hearing).1
+    }
+
+    #[test]
+    fn doppler_rises_on_approach() {
+        let towards = Vec3::new(0.0, 0.0, 20.0);
+        assert_eq!(ahead(Vec3::zero(), Vec3::zero()), 1.0);
+        // Listener moving towards the emitter, then away
+        assert!(ahead(towards, Vec3::zero()) > 1.0);
+        assert!(ahead(-towards, Vec3::zero()) < 1.0);
+        // Emitter moving towards the listener, then away
+        assert!(ahead(Vec3::zero(), -towards) > 1.0);
+        assert!(ahead(Vec3::zero(), towards) < 1.0);
+        // Moving together there's no shift
+        assert!((ahead(towards, towards) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn panning_follows_the_side() {
+        let emitter = |x: f32| Emitter {
+            count: 0,
+            path: EmitterPath {
+                from: Pos3::new(x, 0.0, 0.0),
+                to: Pos3::new(x, 0.0, 0.0),
+                duration: 0.0,
+            },
+            velocity: Vec3::zero(),
+            attenuation: Attenuation::default(),
+        };
+        let hearing = Hearing {
+            listener: Listener::default(),
+            doppler: 0.0,
+        };
+        // Facing +z with +y up, +x is on the listener's left
+        let ([l, r], shift) = place(&emitter(1.0), 0.0, &hearing);
+        assert_eq!(shift, 1.0);
+        assert!(l > r, "{} {}", l, r);
+        let ([l, r], _) = place(&emitter(-1.0), 0.0, &hearing);
+        assert!(r > l, "{} {}", l, r);
+    }
+}
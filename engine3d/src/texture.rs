@@ -33,6 +33,29 @@ impl Texture {
         )
     }
 
+    /// A magenta and black checkerboard, for when the real texture can't be loaded.
+    pub fn fallback(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let img = image::ImageBuffer::from_fn(16, 16, |x, y| {
+            if (x / 4 + y / 4) % 2 == 0 {
+                image::Rgba([255, 0, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 255])
+            }
+        });
+        Self::from_image(
+            device,
+            queue,
+            &image::DynamicImage::ImageRgba8(img),
+            Some("Fallback Texture"),
+            wgpu::AddressMode::Repeat,
+            wgpu::AddressMode::Repeat,
+            wgpu::AddressMode::Repeat,
+            wgpu::FilterMode::Nearest,
+            wgpu::FilterMode::Nearest,
+        )
+        .unwrap()
+    }
+
     pub fn create_depth_texture(
         device: &wgpu::Device,
         sc_desc: &wgpu::SwapChainDescriptor,